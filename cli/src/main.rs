@@ -0,0 +1,481 @@
+// main.rs
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair, Signer},
+    transaction::Transaction,
+};
+use spl_associated_token_account::instruction::create_associated_token_account_idempotent;
+use staking_client::{
+    decode_stake_pool, decode_user_stake, pda, rewards, LockTier, PenaltyConfig, PoolConfigUpdate,
+    PoolKeys, StakePool, Staker,
+};
+use staking_program::{PenaltyCurve, PenaltyDestination, BPS_DENOMINATOR};
+
+#[derive(Parser)]
+#[command(name = "staking-cli", about = "Operate staking_program pools")]
+struct Cli {
+    #[arg(long, env = "STAKING_RPC_URL", default_value = "http://127.0.0.1:8899")]
+    url: String,
+    // Signs and pays for every transaction
+    #[arg(long, env = "STAKING_KEYPAIR")]
+    keypair: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a pool for a staking mint, paying stream 0 in the reward mint
+    CreatePool {
+        #[arg(long)]
+        token_mint: Pubkey,
+        #[arg(long)]
+        reward_mint: Pubkey,
+        /// Lock tiers as `duration_seconds:multiplier_bps`; defaults to one unlocked tier
+        #[arg(long = "lock-tier", value_parser = parse_lock_tier)]
+        lock_tiers: Vec<LockTier>,
+        #[arg(long, default_value_t = 0)]
+        unbonding_period: i64,
+    },
+    /// Escrow a reward campaign for one of the pool's streams
+    Fund {
+        #[arg(long)]
+        token_mint: Pubkey,
+        #[arg(long, default_value_t = 0)]
+        stream: u8,
+        #[arg(long)]
+        amount: u64,
+        /// Unix timestamps; the campaign starts now if omitted
+        #[arg(long)]
+        start: Option<i64>,
+        #[arg(long)]
+        end: i64,
+    },
+    /// Change the emission rate of a stream's current campaign, keeping its
+    /// remaining budget; the campaign ends earlier or later to match
+    SetRewardRate {
+        #[arg(long)]
+        token_mint: Pubkey,
+        #[arg(long, default_value_t = 0)]
+        stream: u8,
+        #[arg(long)]
+        rate: u64,
+    },
+    /// Return a stream's unallocated rewards to the keypair's token account
+    ReclaimRewards {
+        #[arg(long)]
+        token_mint: Pubkey,
+        #[arg(long, default_value_t = 0)]
+        stream: u8,
+    },
+    /// Stake, or buy receipts in liquid pools
+    Stake {
+        #[arg(long)]
+        token_mint: Pubkey,
+        #[arg(long)]
+        amount: u64,
+        #[arg(long, default_value_t = 0)]
+        lock_tier: u8,
+    },
+    /// Unstake directly, or start the cooldown on pools that have one. In
+    /// liquid pools `amount` is in receipts.
+    Unstake {
+        #[arg(long)]
+        token_mint: Pubkey,
+        #[arg(long)]
+        amount: u64,
+    },
+    /// Collect unstaked tokens once the cooldown is over
+    Withdraw {
+        #[arg(long)]
+        token_mint: Pubkey,
+    },
+    Claim {
+        #[arg(long)]
+        token_mint: Pubkey,
+    },
+    UpdateConfig {
+        #[arg(long)]
+        token_mint: Pubkey,
+        #[arg(long = "lock-tier", value_parser = parse_lock_tier)]
+        lock_tiers: Vec<LockTier>,
+        #[arg(long)]
+        unbonding_period: Option<i64>,
+        #[arg(long)]
+        max_total_staked: Option<u64>,
+        #[arg(long)]
+        max_stake_per_user: Option<u64>,
+        #[arg(long)]
+        min_stake_amount: Option<u64>,
+        /// Share of every reward payout sent to the fee recipient; 0 disables the fee
+        #[arg(long)]
+        reward_fee_bps: Option<u16>,
+        /// Wallet whose associated token accounts receive the reward fee
+        #[arg(long)]
+        fee_recipient: Option<Pubkey>,
+    },
+    ShowPool {
+        #[arg(long)]
+        token_mint: Pubkey,
+    },
+    /// Show a wallet's position, including rewards claimable right now
+    ShowPosition {
+        #[arg(long)]
+        token_mint: Pubkey,
+        /// Defaults to the keypair's wallet
+        #[arg(long)]
+        owner: Option<Pubkey>,
+    },
+}
+
+fn parse_lock_tier(value: &str) -> Result<LockTier> {
+    let (duration, multiplier_bps) = value
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected duration_seconds:multiplier_bps"))?;
+    Ok(LockTier {
+        duration: duration.parse()?,
+        multiplier_bps: multiplier_bps.parse()?,
+    })
+}
+
+struct Operator {
+    rpc: RpcClient,
+    payer: Keypair,
+}
+
+impl Operator {
+    fn send(&self, instructions: &[Instruction], extra_signers: &[&Keypair]) -> Result<()> {
+        let mut signers = vec![&self.payer];
+        signers.extend_from_slice(extra_signers);
+        let blockhash = self.rpc.get_latest_blockhash()?;
+        let tx = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.payer.pubkey()),
+            &signers,
+            blockhash,
+        );
+        let signature = self.rpc.send_and_confirm_transaction(&tx)?;
+        println!("{signature}");
+        Ok(())
+    }
+
+    fn token_program(&self, mint: &Pubkey) -> Result<Pubkey> {
+        Ok(self.rpc.get_account(mint).context("fetching mint")?.owner)
+    }
+
+    fn pool(&self, token_mint: &Pubkey) -> Result<(PoolKeys, StakePool)> {
+        let address = pda::stake_pool(token_mint);
+        let data = self.rpc.get_account_data(&address).context("fetching stake pool")?;
+        let pool = decode_stake_pool(&data)?;
+        let keys = PoolKeys::from_pool(address, &pool, self.token_program(token_mint)?);
+        Ok((keys, pool))
+    }
+
+    // The payer's associated accounts for every mint the pool touches
+    fn staker(&self, keys: &PoolKeys) -> Staker {
+        let owner = self.payer.pubkey();
+        Staker {
+            authority: owner,
+            token_account: pda::token_account(&owner, &keys.token_mint, &keys.token_program),
+            reward_accounts: keys
+                .reward_streams
+                .iter()
+                .map(|(mint, _)| pda::token_account(&owner, mint, &keys.token_program))
+                .collect(),
+            receipt_account: keys
+                .receipt_mint
+                .map(|mint| pda::token_account(&owner, &mint, &keys.token_program)),
+            position: None,
+        }
+    }
+
+    // Creates any of the payer's reward and receipt accounts that do not exist
+    // yet, and the fee recipient's account for every stream mint
+    fn create_staker_accounts(&self, keys: &PoolKeys) -> Vec<Instruction> {
+        let owner = self.payer.pubkey();
+        let mut instructions: Vec<Instruction> = keys
+            .reward_streams
+            .iter()
+            .map(|(mint, _)| *mint)
+            .chain(keys.receipt_mint)
+            .map(|mint| {
+                create_associated_token_account_idempotent(&owner, &owner, &mint, &keys.token_program)
+            })
+            .collect();
+        if let Some(recipient) = keys.fee_recipient {
+            // Compounding pays its fee in the staking mint
+            let mints = keys.reward_streams.iter().map(|(mint, _)| *mint);
+            for mint in mints.chain([keys.token_mint]) {
+                instructions.push(create_associated_token_account_idempotent(
+                    &owner,
+                    &recipient,
+                    &mint,
+                    &keys.token_program,
+                ));
+            }
+        }
+        instructions
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let keypair_path = match cli.keypair {
+        Some(path) => path,
+        None => PathBuf::from(std::env::var("HOME")?).join(".config/solana/id.json"),
+    };
+    let payer = read_keypair_file(&keypair_path)
+        .map_err(|err| anyhow!("reading {}: {err}", keypair_path.display()))?;
+    let operator = Operator {
+        rpc: RpcClient::new_with_commitment(cli.url, CommitmentConfig::confirmed()),
+        payer,
+    };
+    let wallet = operator.payer.pubkey();
+
+    match cli.command {
+        Command::CreatePool {
+            token_mint,
+            reward_mint,
+            mut lock_tiers,
+            unbonding_period,
+        } => {
+            if lock_tiers.is_empty() {
+                lock_tiers.push(LockTier {
+                    duration: 0,
+                    multiplier_bps: BPS_DENOMINATOR as u16,
+                });
+            }
+            let token_program = operator.token_program(&token_mint)?;
+            let stake_pool = pda::stake_pool(&token_mint);
+            let no_penalty = PenaltyConfig {
+                curve: PenaltyCurve::None,
+                penalty_bps: 0,
+                destination: PenaltyDestination::Burn,
+                treasury: Pubkey::default(),
+            };
+            operator.send(
+                &[PoolKeys::initialize_stake_pool(
+                    token_mint,
+                    reward_mint,
+                    wallet,
+                    token_program,
+                    lock_tiers,
+                    unbonding_period,
+                    no_penalty,
+                )],
+                &[],
+            )?;
+            println!("stake pool {stake_pool}");
+        }
+        Command::Fund {
+            token_mint,
+            stream,
+            amount,
+            start,
+            end,
+        } => {
+            let (keys, _) = operator.pool(&token_mint)?;
+            let (reward_mint, _) = *keys
+                .reward_streams
+                .get(stream as usize)
+                .ok_or_else(|| anyhow!("pool has no reward stream {stream}"))?;
+            let funder = pda::token_account(&wallet, &reward_mint, &keys.token_program);
+            // Campaigns may not start in the past; leave room for confirmation
+            let start = match start {
+                Some(start) => start,
+                None => operator.rpc.get_block_time(operator.rpc.get_slot()?)? + 30,
+            };
+            operator.send(
+                &[keys.queue_reward_campaign(wallet, funder, stream, amount, start, end)],
+                &[],
+            )?;
+        }
+        Command::SetRewardRate {
+            token_mint,
+            stream,
+            rate,
+        } => {
+            let (keys, _) = operator.pool(&token_mint)?;
+            operator.send(&[keys.set_reward_rate(wallet, stream, rate)], &[])?;
+        }
+        Command::ReclaimRewards { token_mint, stream } => {
+            let (keys, _) = operator.pool(&token_mint)?;
+            let (reward_mint, _) = *keys
+                .reward_streams
+                .get(stream as usize)
+                .ok_or_else(|| anyhow!("pool has no reward stream {stream}"))?;
+            let destination = pda::token_account(&wallet, &reward_mint, &keys.token_program);
+            let create = create_associated_token_account_idempotent(
+                &wallet,
+                &wallet,
+                &reward_mint,
+                &keys.token_program,
+            );
+            operator.send(&[create, keys.reclaim_rewards(wallet, destination, stream)], &[])?;
+        }
+        Command::Stake {
+            token_mint,
+            amount,
+            lock_tier,
+        } => {
+            let (keys, pool) = operator.pool(&token_mint)?;
+            let staker = operator.staker(&keys);
+            let mut instructions = operator.create_staker_accounts(&keys);
+            instructions.push(if pool.is_liquid() {
+                keys.stake_liquid(&staker, amount, None)
+            } else {
+                keys.stake_tokens(&staker, amount, lock_tier, None)
+            });
+            operator.send(&instructions, &[])?;
+        }
+        Command::Unstake { token_mint, amount } => {
+            let (keys, pool) = operator.pool(&token_mint)?;
+            let staker = operator.staker(&keys);
+            let instruction = if pool.is_liquid() {
+                keys.unstake_liquid(&staker, amount)
+            } else if pool.unbonding_period == 0 {
+                keys.unstake_tokens(&staker, amount)
+            } else {
+                keys.request_unstake(&staker, amount)
+            };
+            let mut instructions = operator.create_staker_accounts(&keys);
+            instructions.push(instruction);
+            operator.send(&instructions, &[])?;
+        }
+        Command::Withdraw { token_mint } => {
+            let (keys, _) = operator.pool(&token_mint)?;
+            operator.send(&[keys.withdraw_unstaked(&operator.staker(&keys))], &[])?;
+        }
+        Command::Claim { token_mint } => {
+            let (keys, _) = operator.pool(&token_mint)?;
+            let staker = operator.staker(&keys);
+            let mut instructions = operator.create_staker_accounts(&keys);
+            instructions.push(keys.claim_rewards(&staker));
+            operator.send(&instructions, &[])?;
+        }
+        Command::UpdateConfig {
+            token_mint,
+            lock_tiers,
+            unbonding_period,
+            max_total_staked,
+            max_stake_per_user,
+            min_stake_amount,
+            reward_fee_bps,
+            fee_recipient,
+        } => {
+            let (keys, _) = operator.pool(&token_mint)?;
+            let update = PoolConfigUpdate {
+                lock_tiers: (!lock_tiers.is_empty()).then_some(lock_tiers),
+                unbonding_period,
+                early_exit_penalty: None,
+                max_total_staked,
+                max_stake_per_user,
+                min_stake_amount,
+                reward_fee_bps,
+                fee_recipient,
+            };
+            operator.send(&[keys.update_pool_config(wallet, update)], &[])?;
+        }
+        Command::ShowPool { token_mint } => {
+            let (keys, pool) = operator.pool(&token_mint)?;
+            print_pool(&keys, &pool);
+        }
+        Command::ShowPosition { token_mint, owner } => {
+            let (keys, pool) = operator.pool(&token_mint)?;
+            let owner = owner.unwrap_or(wallet);
+            let now = operator.rpc.get_block_time(operator.rpc.get_slot()?)?;
+            if let Some(receipt_mint) = keys.receipt_mint {
+                let receipt_account = pda::token_account(&owner, &receipt_mint, &keys.token_program);
+                let receipts = operator
+                    .rpc
+                    .get_token_account_balance(&receipt_account)
+                    .context("fetching receipt account")?
+                    .amount
+                    .parse::<u64>()?;
+                let supply = operator.rpc.get_token_supply(&receipt_mint)?.amount.parse::<u64>()?;
+                let value = if receipts == 0 {
+                    0
+                } else {
+                    rewards::receipt_value(&pool, supply, receipts, now)
+                        .map_err(|err| anyhow!("{err}"))?
+                };
+                println!("receipts        {receipts} of {supply}");
+                println!("redeemable      {value}");
+                return Ok(());
+            }
+            let address = pda::user_stake(&owner, &keys.stake_pool);
+            let data = operator
+                .rpc
+                .get_account_data(&address)
+                .context("fetching position")?;
+            let user_stake = decode_user_stake(&data)?;
+
+            let pending = rewards::pending_rewards(&pool, &user_stake, now)
+                .map_err(|err| anyhow!("{err}"))?;
+
+            println!("position        {address}");
+            println!("owner           {}", user_stake.owner);
+            println!("amount          {}", user_stake.amount);
+            println!("effective       {}", user_stake.effective_amount);
+            println!("lock            {} .. {}", user_stake.lock_start, user_stake.lock_end);
+            println!("multiplier_bps  {}", user_stake.multiplier_bps);
+            println!("unbonding       {}", user_stake.unbonding_amount);
+            println!("cooldown_ends   {}", user_stake.cooldown_ends_at);
+            println!("auto_compound   {}", user_stake.auto_compound);
+            for (index, amount) in pending.iter().enumerate() {
+                println!("pending[{index}]      {amount} ({})", keys.reward_streams[index].0);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_pool(keys: &PoolKeys, pool: &StakePool) {
+    println!("stake pool       {}", keys.stake_pool);
+    println!("authority        {}", pool.authority);
+    if pool.pending_authority != Pubkey::default() {
+        println!("pending          {}", pool.pending_authority);
+    }
+    println!("token mint       {} ({})", pool.token_mint, keys.token_program);
+    println!("stake vault      {}", keys.stake_vault);
+    println!("total staked     {}", pool.total_staked);
+    println!("effective stake  {}", pool.total_effective_stake);
+    println!("unbonding        {} (period {}s)", pool.total_unbonding, pool.unbonding_period);
+    println!(
+        "caps             total {} / user {} / min {}",
+        pool.max_total_staked, pool.max_stake_per_user, pool.min_stake_amount
+    );
+    println!("paused           {} (emergency {})", pool.paused, pool.emergency_mode);
+    if let Some(receipt_mint) = keys.receipt_mint {
+        println!("receipt mint     {receipt_mint}");
+    }
+    if let Some(fee_recipient) = keys.fee_recipient {
+        println!("reward fee       {} bps to {fee_recipient}", pool.reward_fee_bps);
+    }
+    for tier in &pool.lock_tiers[..pool.lock_tier_count as usize] {
+        println!("lock tier        {}s x{} bps", tier.duration, tier.multiplier_bps);
+    }
+    for (index, stream) in pool.reward_streams[..pool.reward_stream_count as usize]
+        .iter()
+        .enumerate()
+    {
+        println!(
+            "stream[{index}]        mint {} vault {} rate {}/s {}..{} queued {} fees {} unallocated {}",
+            stream.reward_mint,
+            stream.reward_vault,
+            stream.reward_rate,
+            stream.reward_start,
+            stream.reward_end,
+            stream.queued_campaign.budget,
+            stream.fees_collected,
+            stream.unallocated
+        );
+    }
+}
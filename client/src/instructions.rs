@@ -0,0 +1,678 @@
+// instructions.rs
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{instruction::Instruction, system_program, sysvar};
+use anchor_lang::{InstructionData, ToAccountMetas};
+use anchor_spl::associated_token;
+use anchor_spl::metadata::mpl_token_metadata;
+use staking_program::{
+    accounts, instruction, AllowlistProof, LockTier, PenaltyConfig, PenaltyDestination, PoolConfigUpdate,
+    StakePool,
+};
+
+use crate::pda;
+
+fn build(accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+    Instruction {
+        program_id: staking_program::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// The treasury account a penalty config must be set with, if any
+fn treasury_of(config: &PenaltyConfig) -> Option<Pubkey> {
+    (config.destination == PenaltyDestination::Treasury).then_some(config.treasury)
+}
+
+// Every address an instruction needs that is fixed for the pool
+#[derive(Clone, Debug)]
+pub struct PoolKeys {
+    pub stake_pool: Pubkey,
+    pub token_mint: Pubkey,
+    pub token_program: Pubkey,
+    pub stake_vault: Pubkey,
+    pub receipt_mint: Option<Pubkey>,
+    pub treasury: Option<Pubkey>,
+    pub fee_recipient: Option<Pubkey>, // Set while the pool charges a reward fee
+    pub reward_streams: Vec<(Pubkey, Pubkey)>, // (reward_mint, reward_vault), stream order
+}
+
+// A position's controller: the owning wallet, with the token accounts it uses
+#[derive(Clone, Debug)]
+pub struct Staker {
+    pub authority: Pubkey,
+    pub token_account: Pubkey,
+    pub reward_accounts: Vec<Pubkey>, // One per reward stream, stream order
+    pub receipt_account: Option<Pubkey>,
+    pub position: Option<Position>,
+}
+
+// An NFT position and the holder's token account for it
+#[derive(Clone, Copy, Debug)]
+pub struct Position {
+    pub mint: Pubkey,
+    pub token_account: Pubkey,
+}
+
+impl PoolKeys {
+    pub fn from_pool(stake_pool: Pubkey, pool: &StakePool, token_program: Pubkey) -> Self {
+        let streams = &pool.reward_streams[..pool.reward_stream_count as usize];
+        Self {
+            stake_pool,
+            token_mint: pool.token_mint,
+            token_program,
+            stake_vault: pool.stake_vault,
+            receipt_mint: (pool.receipt_mint != Pubkey::default()).then_some(pool.receipt_mint),
+            treasury: (pool.early_exit_penalty.treasury != Pubkey::default())
+                .then_some(pool.early_exit_penalty.treasury),
+            fee_recipient: (pool.reward_fee_bps > 0).then_some(pool.fee_recipient),
+            reward_streams: streams
+                .iter()
+                .map(|stream| (stream.reward_mint, stream.reward_vault))
+                .collect(),
+        }
+    }
+
+    fn reward_mint(&self) -> Pubkey {
+        self.reward_streams[0].0
+    }
+
+    fn reward_vault(&self) -> Pubkey {
+        self.reward_streams[0].1
+    }
+
+    // The staker's receipt account, by default its associated one
+    fn receipt_account(&self, staker: &Staker) -> Pubkey {
+        let receipt_mint = pda::receipt_mint(&self.stake_pool);
+        staker.receipt_account.unwrap_or_else(|| {
+            pda::token_account(&staker.authority, &receipt_mint, &self.token_program)
+        })
+    }
+
+    // The fee recipient's associated account for `mint`, if the pool charges a fee
+    pub fn fee_account(&self, mint: &Pubkey) -> Option<Pubkey> {
+        self.fee_recipient
+            .map(|recipient| pda::token_account(&recipient, mint, &self.token_program))
+    }
+
+    // (reward_vault, reward_mint, user_reward_account) for every stream past the
+    // first, each followed by the fee account when the pool charges a fee
+    fn extra_reward_accounts(&self, staker: &Staker) -> Vec<AccountMeta> {
+        self.reward_streams
+            .iter()
+            .zip(&staker.reward_accounts)
+            .skip(1)
+            .flat_map(|(&(mint, vault), &destination)| {
+                [
+                    AccountMeta::new(vault, false),
+                    AccountMeta::new_readonly(mint, false),
+                    AccountMeta::new(destination, false),
+                ]
+                .into_iter()
+                .chain(
+                    self.fee_account(&mint)
+                        .map(|fee_account| AccountMeta::new(fee_account, false)),
+                )
+            })
+            .collect()
+    }
+
+    pub fn user_stake(&self, staker: &Staker) -> Pubkey {
+        let owner = staker.position.map_or(staker.authority, |position| position.mint);
+        pda::user_stake(&owner, &self.stake_pool)
+    }
+
+    pub fn initialize_stake_pool(
+        token_mint: Pubkey,
+        reward_mint: Pubkey,
+        authority: Pubkey,
+        token_program: Pubkey,
+        lock_tiers: Vec<LockTier>,
+        unbonding_period: i64,
+        early_exit_penalty: PenaltyConfig,
+    ) -> Instruction {
+        let stake_pool = pda::stake_pool(&token_mint);
+        build(
+            accounts::InitializeStakePool {
+                stake_pool,
+                token_mint,
+                reward_mint,
+                reward_vault: pda::reward_vault(&stake_pool, &reward_mint),
+                stake_vault: pda::stake_vault(&stake_pool),
+                treasury: treasury_of(&early_exit_penalty),
+                authority,
+                token_program,
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            },
+            instruction::InitializeStakePool {
+                lock_tiers,
+                unbonding_period,
+                early_exit_penalty,
+            },
+        )
+    }
+
+    pub fn update_pool_config(&self, authority: Pubkey, update: PoolConfigUpdate) -> Instruction {
+        build(
+            accounts::UpdatePoolConfig {
+                stake_pool: self.stake_pool,
+                treasury: update.early_exit_penalty.as_ref().and_then(treasury_of),
+                authority,
+            },
+            instruction::UpdatePoolConfig { update },
+        )
+    }
+
+    pub fn propose_authority(&self, authority: Pubkey, new_authority: Pubkey) -> Instruction {
+        build(
+            accounts::ManageAuthority {
+                stake_pool: self.stake_pool,
+                authority,
+            },
+            instruction::ProposeAuthority { new_authority },
+        )
+    }
+
+    pub fn accept_authority(&self, new_authority: Pubkey) -> Instruction {
+        build(
+            accounts::AcceptAuthority {
+                stake_pool: self.stake_pool,
+                new_authority,
+            },
+            instruction::AcceptAuthority {},
+        )
+    }
+
+    pub fn cancel_authority_transfer(&self, authority: Pubkey) -> Instruction {
+        build(
+            accounts::ManageAuthority {
+                stake_pool: self.stake_pool,
+                authority,
+            },
+            instruction::CancelAuthorityTransfer {},
+        )
+    }
+
+    pub fn renounce_authority(&self, authority: Pubkey) -> Instruction {
+        build(
+            accounts::ManageAuthority {
+                stake_pool: self.stake_pool,
+                authority,
+            },
+            instruction::RenounceAuthority {},
+        )
+    }
+
+    pub fn set_paused(&self, authority: Pubkey, paused: bool) -> Instruction {
+        build(
+            accounts::SetPaused {
+                stake_pool: self.stake_pool,
+                authority,
+            },
+            instruction::SetPaused { paused },
+        )
+    }
+
+    pub fn enable_emergency_mode(&self, authority: Pubkey) -> Instruction {
+        build(
+            accounts::SetPaused {
+                stake_pool: self.stake_pool,
+                authority,
+            },
+            instruction::EnableEmergencyMode {},
+        )
+    }
+
+    pub fn set_allowlist_root(&self, authority: Pubkey, root: [u8; 32]) -> Instruction {
+        build(
+            accounts::SetAllowlistRoot {
+                stake_pool: self.stake_pool,
+                authority,
+            },
+            instruction::SetAllowlistRoot { root },
+        )
+    }
+
+    pub fn add_reward_stream(&self, authority: Pubkey, reward_mint: Pubkey) -> Instruction {
+        build(
+            accounts::AddRewardStream {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                reward_mint,
+                reward_vault: pda::reward_vault(&self.stake_pool, &reward_mint),
+                authority,
+                token_program: self.token_program,
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            },
+            instruction::AddRewardStream {},
+        )
+    }
+
+    pub fn set_reward_rate(&self, authority: Pubkey, stream_index: u8, reward_rate: u64) -> Instruction {
+        build(
+            accounts::SetRewardRate {
+                stake_pool: self.stake_pool,
+                authority,
+            },
+            instruction::SetRewardRate {
+                stream_index,
+                reward_rate,
+            },
+        )
+    }
+
+    pub fn queue_reward_campaign(
+        &self,
+        authority: Pubkey,
+        funder_token_account: Pubkey,
+        stream_index: u8,
+        budget: u64,
+        reward_start: i64,
+        reward_end: i64,
+    ) -> Instruction {
+        let (reward_mint, reward_vault) = self.reward_streams[stream_index as usize];
+        build(
+            accounts::QueueRewardCampaign {
+                stake_pool: self.stake_pool,
+                reward_vault,
+                reward_mint,
+                funder_token_account,
+                authority,
+                token_program: self.token_program,
+            },
+            instruction::QueueRewardCampaign {
+                stream_index,
+                budget,
+                reward_start,
+                reward_end,
+            },
+        )
+    }
+
+    pub fn reclaim_rewards(
+        &self,
+        authority: Pubkey,
+        destination: Pubkey,
+        stream_index: u8,
+    ) -> Instruction {
+        let (reward_mint, reward_vault) = self.reward_streams[stream_index as usize];
+        build(
+            accounts::ReclaimRewards {
+                stake_pool: self.stake_pool,
+                reward_vault,
+                reward_mint,
+                destination,
+                authority,
+                token_program: self.token_program,
+            },
+            instruction::ReclaimRewards { stream_index },
+        )
+    }
+
+    pub fn stake_tokens(
+        &self,
+        staker: &Staker,
+        amount: u64,
+        lock_tier: u8,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Instruction {
+        build(
+            accounts::StakeTokens {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                user_stake: self.user_stake(staker),
+                pool_token_account: self.stake_vault,
+                user_token_account: staker.token_account,
+                user_authority: staker.authority,
+                token_program: self.token_program,
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            },
+            instruction::StakeTokens {
+                amount,
+                lock_tier,
+                allowlist_proof,
+            },
+        )
+    }
+
+    // `position_mint` is a fresh keypair that must also sign
+    pub fn open_position(
+        &self,
+        staker: &Staker,
+        position_mint: Pubkey,
+        amount: u64,
+        lock_tier: u8,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Instruction {
+        build(
+            accounts::OpenPosition {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                position_config: pda::position_config(&self.stake_pool),
+                position_mint,
+                user_stake: pda::user_stake(&position_mint, &self.stake_pool),
+                wallet_stake: pda::wallet_stake(&staker.authority, &self.stake_pool),
+                position_token_account: pda::token_account(
+                    &staker.authority,
+                    &position_mint,
+                    &self.token_program,
+                ),
+                position_metadata: pda::position_metadata(&position_mint),
+                pool_token_account: self.stake_vault,
+                user_token_account: staker.token_account,
+                user_authority: staker.authority,
+                token_program: self.token_program,
+                associated_token_program: associated_token::ID,
+                metadata_program: mpl_token_metadata::ID,
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            },
+            instruction::OpenPosition {
+                amount,
+                lock_tier,
+                allowlist_proof,
+            },
+        )
+    }
+
+    pub fn unstake_tokens(&self, staker: &Staker, amount: u64) -> Instruction {
+        let mut ix = build(
+            accounts::UnstakeTokens {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                reward_mint: self.reward_mint(),
+                user_stake: self.user_stake(staker),
+                pool_token_account: self.stake_vault,
+                user_token_account: staker.token_account,
+                reward_vault: self.reward_vault(),
+                user_reward_account: staker.reward_accounts[0],
+                treasury: self.treasury,
+                fee_account: self.fee_account(&self.reward_mint()),
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+            },
+            instruction::UnstakeTokens { amount },
+        );
+        ix.accounts.extend(self.extra_reward_accounts(staker));
+        ix
+    }
+
+    pub fn request_unstake(&self, staker: &Staker, amount: u64) -> Instruction {
+        build(
+            accounts::RequestUnstake {
+                stake_pool: self.stake_pool,
+                user_stake: self.user_stake(staker),
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+            },
+            instruction::RequestUnstake { amount },
+        )
+    }
+
+    pub fn withdraw_unstaked(&self, staker: &Staker) -> Instruction {
+        build(
+            accounts::WithdrawUnstaked {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                user_stake: self.user_stake(staker),
+                pool_token_account: self.stake_vault,
+                user_token_account: staker.token_account,
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+            },
+            instruction::WithdrawUnstaked {},
+        )
+    }
+
+    pub fn cancel_unstake(&self, staker: &Staker) -> Instruction {
+        build(
+            accounts::CancelUnstake {
+                stake_pool: self.stake_pool,
+                user_stake: self.user_stake(staker),
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+            },
+            instruction::CancelUnstake {},
+        )
+    }
+
+    pub fn emergency_withdraw(&self, staker: &Staker) -> Instruction {
+        build(
+            accounts::EmergencyWithdraw {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                user_stake: self.user_stake(staker),
+                pool_token_account: self.stake_vault,
+                user_token_account: staker.token_account,
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+            },
+            instruction::EmergencyWithdraw {},
+        )
+    }
+
+    pub fn claim_rewards(&self, staker: &Staker) -> Instruction {
+        let mut ix = build(
+            accounts::ClaimRewards {
+                stake_pool: self.stake_pool,
+                reward_mint: self.reward_mint(),
+                user_stake: self.user_stake(staker),
+                reward_vault: self.reward_vault(),
+                user_reward_account: staker.reward_accounts[0],
+                fee_account: self.fee_account(&self.reward_mint()),
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+            },
+            instruction::ClaimRewards {},
+        );
+        ix.accounts.extend(self.extra_reward_accounts(staker));
+        ix
+    }
+
+    pub fn compound(&self, staker: &Staker, allowlist_proof: Option<AllowlistProof>) -> Instruction {
+        build(
+            accounts::Compound {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                user_stake: self.user_stake(staker),
+                pool_token_account: self.stake_vault,
+                reward_vault: self.reward_vault(),
+                fee_account: self.fee_account(&self.token_mint),
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+            },
+            instruction::Compound { allowlist_proof },
+        )
+    }
+
+    pub fn set_auto_compound(&self, staker: &Staker, enabled: bool) -> Instruction {
+        build(
+            accounts::SetAutoCompound {
+                stake_pool: self.stake_pool,
+                user_stake: self.user_stake(staker),
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+            },
+            instruction::SetAutoCompound { enabled },
+        )
+    }
+
+    // Permissionless; `allowlist_proof` is the position owner's, on allowlisted pools
+    pub fn compound_for(
+        &self,
+        user_stake: Pubkey,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Instruction {
+        build(
+            accounts::CompoundFor {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                user_stake,
+                pool_token_account: self.stake_vault,
+                reward_vault: self.reward_vault(),
+                fee_account: self.fee_account(&self.token_mint),
+                token_program: self.token_program,
+            },
+            instruction::CompoundFor { allowlist_proof },
+        )
+    }
+
+    // Permissionless
+    pub fn expire_lock(&self, user_stake: Pubkey) -> Instruction {
+        build(
+            accounts::ExpireLock {
+                stake_pool: self.stake_pool,
+                user_stake,
+            },
+            instruction::ExpireLock {},
+        )
+    }
+
+    pub fn enable_receipt_mint(&self, authority: Pubkey) -> Instruction {
+        build(
+            accounts::EnableReceiptMint {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                receipt_mint: pda::receipt_mint(&self.stake_pool),
+                authority,
+                token_program: self.token_program,
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            },
+            instruction::EnableReceiptMint {},
+        )
+    }
+
+    // The staker's receipt account must already exist
+    pub fn stake_liquid(
+        &self,
+        staker: &Staker,
+        amount: u64,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Instruction {
+        build(
+            accounts::StakeLiquid {
+                stake_pool: self.stake_pool,
+                wallet_stake: pda::wallet_stake(&staker.authority, &self.stake_pool),
+                token_mint: self.token_mint,
+                receipt_mint: pda::receipt_mint(&self.stake_pool),
+                reward_vault: self.reward_vault(),
+                user_token_account: staker.token_account,
+                user_receipt_account: self.receipt_account(staker),
+                pool_token_account: self.stake_vault,
+                fee_account: self.fee_account(&self.token_mint),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+                system_program: system_program::ID,
+            },
+            instruction::StakeLiquid {
+                amount,
+                allowlist_proof,
+            },
+        )
+    }
+
+    pub fn unstake_liquid(&self, staker: &Staker, receipts: u64) -> Instruction {
+        build(
+            accounts::UnstakeLiquid {
+                stake_pool: self.stake_pool,
+                token_mint: self.token_mint,
+                receipt_mint: pda::receipt_mint(&self.stake_pool),
+                reward_vault: self.reward_vault(),
+                user_token_account: staker.token_account,
+                user_receipt_account: self.receipt_account(staker),
+                pool_token_account: self.stake_vault,
+                fee_account: self.fee_account(&self.token_mint),
+                user_authority: staker.authority,
+                token_program: self.token_program,
+            },
+            instruction::UnstakeLiquid { receipts },
+        )
+    }
+
+    pub fn enable_position_nfts(
+        &self,
+        authority: Pubkey,
+        name: String,
+        symbol: String,
+        uri: String,
+    ) -> Instruction {
+        build(
+            accounts::EnablePositionNfts {
+                stake_pool: self.stake_pool,
+                position_config: pda::position_config(&self.stake_pool),
+                authority,
+                system_program: system_program::ID,
+            },
+            instruction::EnablePositionNfts { name, symbol, uri },
+        )
+    }
+
+    pub fn get_voting_power(&self, user_stake: Pubkey, timestamp: i64) -> Instruction {
+        build(
+            accounts::GetVotingPower {
+                stake_pool: self.stake_pool,
+                user_stake,
+            },
+            instruction::GetVotingPower { timestamp },
+        )
+    }
+
+    pub fn get_total_voting_power(&self, timestamp: i64) -> Instruction {
+        build(
+            accounts::GetTotalVotingPower {
+                stake_pool: self.stake_pool,
+            },
+            instruction::GetTotalVotingPower { timestamp },
+        )
+    }
+
+    // Both the pool authority and the realm authority sign
+    pub fn create_registrar(
+        &self,
+        authority: Pubkey,
+        realm_authority: Pubkey,
+        realm: Pubkey,
+        governance_program: Pubkey,
+        voting_period: i64,
+    ) -> Instruction {
+        build(
+            accounts::CreateRegistrar {
+                registrar: pda::registrar(&realm, &self.token_mint),
+                stake_pool: self.stake_pool,
+                realm,
+                governance_program,
+                realm_authority,
+                authority,
+                system_program: system_program::ID,
+            },
+            instruction::CreateRegistrar { voting_period },
+        )
+    }
+
+    pub fn update_voter_weight_record(&self, staker: &Staker) -> Instruction {
+        let user_stake = self.user_stake(staker);
+        build(
+            accounts::UpdateVoterWeightRecord {
+                stake_pool: self.stake_pool,
+                user_stake,
+                voter_weight_record: pda::voter_weight_record(&user_stake),
+                max_voter_weight_record: pda::max_voter_weight_record(&self.stake_pool),
+                position_token_account: staker.position.map(|position| position.token_account),
+                user_authority: staker.authority,
+                system_program: system_program::ID,
+            },
+            instruction::UpdateVoterWeightRecord {},
+        )
+    }
+}
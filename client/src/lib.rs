@@ -0,0 +1,24 @@
+// lib.rs
+//
+// Client-side helpers for staking_program. Instruction data and account
+// layouts come straight from the program crate, and the reward math in
+// `rewards` runs the program's own code, so the two cannot drift apart.
+use anchor_lang::prelude::*;
+use anchor_lang::AccountDeserialize;
+
+pub mod instructions;
+pub mod pda;
+pub mod rewards;
+
+pub use instructions::{PoolKeys, Position, Staker};
+pub use staking_program::{
+    AllowlistProof, LockTier, PenaltyConfig, PoolConfigUpdate, StakePool, UserStake, ID,
+};
+
+pub fn decode_stake_pool(data: &[u8]) -> Result<StakePool> {
+    StakePool::try_deserialize(&mut &data[..])
+}
+
+pub fn decode_user_stake(data: &[u8]) -> Result<UserStake> {
+    UserStake::try_deserialize(&mut &data[..])
+}
@@ -0,0 +1,68 @@
+// pda.rs
+use anchor_lang::prelude::*;
+use anchor_spl::associated_token::get_associated_token_address_with_program_id;
+use anchor_spl::metadata::mpl_token_metadata;
+
+fn find(seeds: &[&[u8]]) -> Pubkey {
+    Pubkey::find_program_address(seeds, &staking_program::ID).0
+}
+
+pub fn stake_pool(token_mint: &Pubkey) -> Pubkey {
+    find(&[b"stake_pool", token_mint.as_ref()])
+}
+
+// `owner` is the wallet, or the position mint for NFT positions
+pub fn user_stake(owner: &Pubkey, stake_pool: &Pubkey) -> Pubkey {
+    find(&[b"user_stake", owner.as_ref(), stake_pool.as_ref()])
+}
+
+// Tracks what `wallet` has deposited into an NFT or liquid pool
+pub fn wallet_stake(wallet: &Pubkey, stake_pool: &Pubkey) -> Pubkey {
+    find(&[b"wallet_stake", wallet.as_ref(), stake_pool.as_ref()])
+}
+
+pub fn reward_vault(stake_pool: &Pubkey, reward_mint: &Pubkey) -> Pubkey {
+    find(&[b"reward_vault", stake_pool.as_ref(), reward_mint.as_ref()])
+}
+
+pub fn stake_vault(stake_pool: &Pubkey) -> Pubkey {
+    find(&[b"stake_vault", stake_pool.as_ref()])
+}
+
+pub fn receipt_mint(stake_pool: &Pubkey) -> Pubkey {
+    find(&[b"receipt_mint", stake_pool.as_ref()])
+}
+
+pub fn position_config(stake_pool: &Pubkey) -> Pubkey {
+    find(&[b"position_nft_config", stake_pool.as_ref()])
+}
+
+pub fn registrar(realm: &Pubkey, governing_token_mint: &Pubkey) -> Pubkey {
+    find(&[b"registrar", realm.as_ref(), governing_token_mint.as_ref()])
+}
+
+pub fn voter_weight_record(user_stake: &Pubkey) -> Pubkey {
+    find(&[b"voter_weight_record", user_stake.as_ref()])
+}
+
+pub fn max_voter_weight_record(stake_pool: &Pubkey) -> Pubkey {
+    find(&[b"max_voter_weight_record", stake_pool.as_ref()])
+}
+
+pub fn position_metadata(position_mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            b"metadata",
+            mpl_token_metadata::ID.as_ref(),
+            position_mint.as_ref(),
+        ],
+        &mpl_token_metadata::ID,
+    )
+    .0
+}
+
+
+// Associated token account of `owner`, for the user side of transfers
+pub fn token_account(owner: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    get_associated_token_address_with_program_id(owner, mint, token_program)
+}
@@ -0,0 +1,37 @@
+// rewards.rs
+use anchor_lang::prelude::*;
+use staking_program::{StakePool, UserStake};
+
+// Rewards a position could claim at `now`, per stream and net of the protocol
+// fee, found by running the program's own settlement on copies of the accounts
+pub fn pending_rewards(pool: &StakePool, user_stake: &UserStake, now: i64) -> Result<Vec<u64>> {
+    let mut pool = pool.clone();
+    let mut user_stake = user_stake.clone();
+    pool.checkpoint(&mut user_stake, now)?;
+    user_stake.rewards[..pool.reward_stream_count as usize]
+        .iter()
+        .map(|reward| {
+            let fee = pool.reward_fee(reward.pending_rewards)?;
+            Ok(reward.pending_rewards - fee)
+        })
+        .collect()
+}
+
+// Stake that `receipts` of a liquid pool would redeem for at `now`, with the
+// rewards a harvest would restake first; Token-2022 transfer fees not included
+pub fn receipt_value(pool: &StakePool, receipt_supply: u64, receipts: u64, now: i64) -> Result<u64> {
+    let mut pool = pool.clone();
+    if !pool.emergency_mode {
+        let (reward, _fee) = pool.harvest_liquid(now)?;
+        pool.add_liquid_rewards(reward)?;
+    }
+    pool.redeem_liquid(receipts, receipt_supply)
+}
+
+// Position and pool-wide voting power at `timestamp`
+pub fn voting_power(pool: &StakePool, user_stake: &UserStake, timestamp: i64) -> Result<(u64, u64)> {
+    Ok((
+        user_stake.voting_power_at(timestamp)?,
+        pool.voting_power.total_at(timestamp)?,
+    ))
+}
@@ -0,0 +1,374 @@
+// staking_transitions.rs
+//
+// Drives random sequences of stakes, unstakes, claims, campaigns, reclaims and
+// clock advances through the program's own state transitions, with every token
+// account modelled as a balance keyed by its address. Vaults live at the
+// addresses the program derives for them, so two vaults that resolve to one
+// account share one balance. Each step applies atomically like a transaction:
+// a rejected step rolls back, and only a panic is a failure. After every step
+// the pool must account for every user and stay solvent.
+//
+// Run with `cargo fuzz run staking_transitions` from the repository root.
+#![no_main]
+
+use anchor_lang::prelude::*;
+use arbitrary::Arbitrary;
+use std::collections::{BTreeMap, BTreeSet};
+use libfuzzer_sys::fuzz_target;
+use staking_program::{
+    LockTier, PenaltyConfig, PenaltyCurve, PenaltyDestination, RewardCampaign, RewardStream,
+    StakePool, UserReward, UserStake, VotingPower, BPS_DENOMINATOR, MAX_LOCK_DURATION,
+    MAX_LOCK_TIERS, MAX_REWARD_FEE_BPS, MAX_REWARD_STREAMS, VE_SLOPE_SLOTS,
+};
+
+const USERS: usize = 8;
+const START: i64 = 1_700_000_000;
+// Users and the reward funder split one mint's supply, as they would on chain
+const WALLET: u64 = u64::MAX / (USERS as u64 + 1);
+
+#[derive(Arbitrary, Debug)]
+struct Scenario {
+    lock_tiers: Vec<(u32, u16)>, // (duration, multiplier bonus); tier 0 is always unlocked
+    penalty_curve: u8,
+    penalty_bps: u16,
+    redistribute: bool,
+    reward_fee_bps: u16,
+    actions: Vec<Action>,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Action {
+    Stake { user: u8, amount: u64, lock_tier: u8 },
+    Unstake { user: u8, amount: u64 },
+    Claim { user: u8 },
+    Fund { budget: u64, delay: u16, duration: u32 },
+    SetRate { rate: u64 },
+    Reclaim,
+    Advance { seconds: u32 },
+}
+
+// A step the program refused; the state is rolled back
+struct Rejected;
+
+impl From<anchor_lang::error::Error> for Rejected {
+    fn from(_: anchor_lang::error::Error) -> Self {
+        Rejected
+    }
+}
+
+#[derive(Clone)]
+struct World {
+    now: i64,
+    pool: StakePool,
+    users: Vec<UserStake>,
+    wallets: Vec<Pubkey>, // Each user's token account
+    funder: Pubkey,
+    fee_wallet: Pubkey,
+    balances: BTreeMap<Pubkey, u64>,
+}
+
+fn new_pool(scenario: &Scenario) -> Option<StakePool> {
+    // Stream 0 pays the staking mint so penalties can be redistributed
+    let mint = Pubkey::new_unique();
+    let (stake_pool, _) =
+        Pubkey::find_program_address(&[b"stake_pool", mint.as_ref()], &staking_program::ID);
+    let (stake_vault, _) = Pubkey::find_program_address(
+        &[b"stake_vault", stake_pool.as_ref()],
+        &staking_program::ID,
+    );
+    let (reward_vault, _) = Pubkey::find_program_address(
+        &[b"reward_vault", stake_pool.as_ref(), mint.as_ref()],
+        &staking_program::ID,
+    );
+    let mut pool = StakePool {
+        authority: Pubkey::new_unique(),
+        pending_authority: Pubkey::default(),
+        token_mint: mint,
+        stake_vault,
+        total_staked: 0,
+        total_effective_stake: 0,
+        total_unbonding: 0,
+        unbonding_period: 0,
+        early_exit_penalty: PenaltyConfig {
+            curve: PenaltyCurve::None,
+            penalty_bps: 0,
+            destination: PenaltyDestination::Burn,
+            treasury: Pubkey::default(),
+        },
+        reward_fee_bps: 0,
+        fee_recipient: Pubkey::default(),
+        max_total_staked: 0,
+        max_stake_per_user: 0,
+        min_stake_amount: 0,
+        allowlist_root: [0; 32],
+        receipt_mint: Pubkey::default(),
+        liquid_reward_index: 0,
+        liquid_deferred_rewards: 0,
+        position_nfts: false,
+        paused: false,
+        emergency_mode: false,
+        voting_power: VotingPower {
+            bias: 0,
+            slope: 0,
+            last_checkpoint: START,
+            slope_changes: [0; VE_SLOPE_SLOTS],
+        },
+        realm: Pubkey::default(),
+        realm_governing_token_mint: Pubkey::default(),
+        realm_voting_period: 0,
+        lock_tier_count: 0,
+        lock_tiers: [LockTier::default(); MAX_LOCK_TIERS],
+        reward_stream_count: 1,
+        reward_streams: [RewardStream::default(); MAX_REWARD_STREAMS],
+        bump: 255,
+    };
+    pool.reward_streams[0] = RewardStream::new(mint, reward_vault, START);
+
+    let mut tiers = vec![LockTier {
+        duration: 0,
+        multiplier_bps: BPS_DENOMINATOR as u16,
+    }];
+    for &(duration, bonus) in scenario.lock_tiers.iter().take(MAX_LOCK_TIERS - 1) {
+        tiers.push(LockTier {
+            duration: 1 + duration as i64 % MAX_LOCK_DURATION,
+            multiplier_bps: BPS_DENOMINATOR as u16 + bonus % (u16::MAX - BPS_DENOMINATOR as u16),
+        });
+    }
+    pool.set_lock_tiers(&tiers).ok()?;
+    pool.set_early_exit_penalty(PenaltyConfig {
+        curve: match scenario.penalty_curve % 3 {
+            0 => PenaltyCurve::None,
+            1 => PenaltyCurve::Flat,
+            _ => PenaltyCurve::LinearDecay,
+        },
+        penalty_bps: scenario.penalty_bps % (BPS_DENOMINATOR as u16 + 1),
+        destination: if scenario.redistribute {
+            PenaltyDestination::Redistribute
+        } else {
+            PenaltyDestination::Burn
+        },
+        treasury: Pubkey::default(),
+    })
+    .ok()?;
+    pool.set_reward_fee(
+        scenario.reward_fee_bps % (MAX_REWARD_FEE_BPS + 1),
+        Pubkey::new_unique(),
+    )
+    .ok()?;
+    Some(pool)
+}
+
+fn new_user_stake() -> UserStake {
+    UserStake {
+        owner: Pubkey::new_unique(),
+        nft_position: false,
+        amount: 0,
+        effective_amount: 0,
+        staked_at: 0,
+        lock_start: 0,
+        lock_end: 0,
+        multiplier_bps: BPS_DENOMINATOR as u16,
+        unbonding_amount: 0,
+        cooldown_ends_at: 0,
+        auto_compound: false,
+        voting_amount: 0,
+        voting_lock_end: 0,
+        rewards: [UserReward::default(); MAX_REWARD_STREAMS],
+        bump: 255,
+    }
+}
+
+impl World {
+    fn balance(&self, account: &Pubkey) -> u64 {
+        self.balances.get(account).copied().unwrap_or(0)
+    }
+
+    fn burn(&mut self, account: Pubkey, amount: u64) -> std::result::Result<(), Rejected> {
+        let balance = self.balance(&account).checked_sub(amount).ok_or(Rejected)?;
+        self.balances.insert(account, balance);
+        Ok(())
+    }
+
+    fn transfer(
+        &mut self,
+        from: Pubkey,
+        to: Pubkey,
+        amount: u64,
+    ) -> std::result::Result<(), Rejected> {
+        self.burn(from, amount)?;
+        let balance = self.balance(&to).checked_add(amount).ok_or(Rejected)?;
+        self.balances.insert(to, balance);
+        Ok(())
+    }
+
+    // Stream 0's payout, as pay_rewards makes it
+    fn pay_rewards(&mut self, user: usize) -> std::result::Result<(), Rejected> {
+        let reward = self.users[user].rewards[0].pending_rewards;
+        self.users[user].rewards[0].pending_rewards = 0;
+        let fee = self.pool.reward_fee(reward)?;
+        let reward_vault = self.pool.reward_streams[0].reward_vault;
+        self.transfer(reward_vault, self.fee_wallet, fee)?;
+        self.transfer(reward_vault, self.wallets[user], reward - fee)?;
+        let mut fees = [0; MAX_REWARD_STREAMS];
+        fees[0] = fee;
+        self.pool.record_fees(&fees)?;
+        Ok(())
+    }
+
+    fn apply(&mut self, action: &Action) -> std::result::Result<(), Rejected> {
+        let now = self.now;
+        let stake_vault = self.pool.stake_vault;
+        let reward_vault = self.pool.reward_streams[0].reward_vault;
+        match *action {
+            Action::Stake {
+                user,
+                amount,
+                lock_tier,
+            } => {
+                let user = user as usize % USERS;
+                self.transfer(self.wallets[user], stake_vault, amount)?;
+                let user_stake = &mut self.users[user];
+                let tier = self.pool.lock_tier(lock_tier)?;
+                self.pool.checkpoint(user_stake, now)?;
+                self.pool.check_stake_limits(user_stake.amount, amount)?;
+                self.pool.deposit(user_stake, tier, amount, now)?;
+            }
+            Action::Unstake { user, amount } => {
+                let user = user as usize % USERS;
+                let user_stake = &mut self.users[user];
+                self.pool.checkpoint(user_stake, now)?;
+                let penalty = self.pool.withdraw(user_stake, amount, now)?;
+
+                // Burned instead when nobody is left to share it
+                let total_effective_stake = self.pool.total_effective_stake;
+                let redistribute = self.pool.early_exit_penalty.destination
+                    == PenaltyDestination::Redistribute
+                    && total_effective_stake > 0;
+                if penalty > 0 && redistribute {
+                    self.pool.reward_streams[0].distribute(penalty, total_effective_stake)?;
+                }
+                self.transfer(stake_vault, self.wallets[user], amount - penalty)?;
+                if redistribute {
+                    self.transfer(stake_vault, reward_vault, penalty)?;
+                } else {
+                    self.burn(stake_vault, penalty)?;
+                }
+                self.pay_rewards(user)?;
+            }
+            Action::Claim { user } => {
+                let user = user as usize % USERS;
+                let user_stake = &mut self.users[user];
+                self.pool.checkpoint(user_stake, now)?;
+                self.pool.sync_effective_stake(user_stake, now)?;
+                self.pay_rewards(user)?;
+            }
+            Action::Fund {
+                budget,
+                delay,
+                duration,
+            } => {
+                let start = now + delay as i64;
+                let campaign = RewardCampaign {
+                    budget,
+                    start,
+                    end: start + duration as i64,
+                };
+                self.pool.update_rewards(now)?;
+                self.pool.stream_mut(0)?.queue_campaign(campaign, now)?;
+                self.transfer(self.funder, reward_vault, budget)?;
+            }
+            Action::SetRate { rate } => {
+                self.pool.update_rewards(now)?;
+                self.pool.stream_mut(0)?.set_rate(rate, now)?;
+            }
+            Action::Reclaim => {
+                self.pool.update_rewards(now)?;
+                let amount = std::mem::take(&mut self.pool.reward_streams[0].unallocated);
+                if amount == 0 {
+                    return Err(Rejected);
+                }
+                self.transfer(reward_vault, self.funder, amount)?;
+            }
+            Action::Advance { seconds } => {
+                self.now += seconds as i64;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_invariants(&self) {
+        let staked: u128 = self.users.iter().map(|user| user.amount as u128).sum();
+        assert_eq!(staked, self.pool.total_staked as u128, "user amounts != total_staked");
+        let effective: u128 = self
+            .users
+            .iter()
+            .map(|user| user.effective_amount as u128)
+            .sum();
+        assert_eq!(
+            effective, self.pool.total_effective_stake as u128,
+            "effective amounts != total_effective_stake"
+        );
+        let stake_vault = self.balance(&self.pool.stake_vault);
+        assert!(
+            stake_vault >= self.pool.total_staked,
+            "stake vault {stake_vault} below total_staked {}",
+            self.pool.total_staked
+        );
+
+        assert_eq!(
+            self.pool.reward_streams[0].fees_collected,
+            self.balance(&self.fee_wallet),
+            "fees_collected != fees paid"
+        );
+
+        // Everything claimable right now must already sit in the reward vault
+        let mut pool = self.pool.clone();
+        let mut owed: u128 = 0;
+        for user in &self.users {
+            let mut user = user.clone();
+            pool.checkpoint(&mut user, self.now)
+                .expect("settling a position failed");
+            owed += user.rewards[0].pending_rewards as u128;
+        }
+        let reward_vault = self.balance(&self.pool.reward_streams[0].reward_vault);
+        assert!(
+            owed <= reward_vault as u128,
+            "owed rewards {owed} exceed reward vault {reward_vault}"
+        );
+
+        // Principal and rewards together, counting a shared vault once
+        let vaults: BTreeSet<Pubkey> =
+            [self.pool.stake_vault, self.pool.reward_streams[0].reward_vault].into();
+        let held: u128 = vaults.iter().map(|vault| self.balance(vault) as u128).sum();
+        assert!(
+            held >= self.pool.total_staked as u128 + owed,
+            "vaults hold {held}, below total_staked {} plus owed rewards {owed}",
+            self.pool.total_staked
+        );
+    }
+}
+
+fuzz_target!(|scenario: Scenario| {
+    let Some(pool) = new_pool(&scenario) else {
+        return;
+    };
+    let wallets: Vec<Pubkey> = (0..USERS).map(|_| Pubkey::new_unique()).collect();
+    let funder = Pubkey::new_unique();
+    let mut world = World {
+        now: START,
+        pool,
+        users: (0..USERS).map(|_| new_user_stake()).collect(),
+        balances: wallets.iter().chain([&funder]).map(|&wallet| (wallet, WALLET)).collect(),
+        wallets,
+        funder,
+        fee_wallet: Pubkey::new_unique(),
+    };
+
+    for action in &scenario.actions {
+        let before = world.clone();
+        if world.apply(action).is_err() {
+            world = before;
+        }
+        world.check_invariants();
+    }
+});
@@ -1,103 +1,1359 @@
 // lib.rs
 use anchor_lang::prelude::*;
-use anchor_lang::solana_program::program::invoke_signed;
-use anchor_lang::solana_program::system_instruction;
+use anchor_lang::solana_program::keccak;
 use anchor_spl::{
     associated_token::AssociatedToken,
     metadata::{self, Metadata, mpl_token_metadata::types::DataV2},
-    token::{self, Mint, Token, TokenAccount, Transfer},
+    token_2022::{
+        self,
+        spl_token_2022::{
+            self,
+            extension::{transfer_fee::TransferFeeConfig, BaseStateWithExtensions, ExtensionType, StateWithExtensions},
+            instruction::AuthorityType,
+        },
+    },
+    token_interface::{
+        self, Burn, Mint, MintTo, SetAuthority, TokenAccount,
+        TokenInterface, TransferChecked,
+    },
 };
 
-declare_id!("Stake11111111111111111111111111111111111111");
+pub mod math;
+
+use math::Rounding;
+
+declare_id!("Ac88Sy61h63UswBS7jo2irh68NuqRv3KwNKjRQPZkCJe");
+
+// Fixed-point scale applied to the reward-per-token accumulators
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+
+// Stream 0 is created with the pool; the rest are added by the authority
+pub const MAX_REWARD_STREAMS: usize = 4;
+
+// Lock multipliers are expressed in basis points; 10_000 is no boost
+pub const BPS_DENOMINATOR: u64 = 10_000;
+pub const MAX_LOCK_TIERS: usize = 4;
+
+pub const MAX_UNBONDING_PERIOD: i64 = 90 * 86_400;
+
+// Hard ceiling on the protocol's cut of reward payouts
+pub const MAX_REWARD_FEE_BPS: u16 = 2_000;
+
+// Vote-escrow power decays linearly to zero at the end of a lock, reaching the
+// full locked amount only for a lock of MAX_LOCK_DURATION. Lock ends are rounded
+// down to whole weeks so the pool total only changes slope on week boundaries.
+pub const WEEK: i64 = 7 * 86_400;
+pub const MAX_LOCK_DURATION: i64 = 104 * WEEK;
+pub const VE_SLOPE_SLOTS: usize = 128; // Ring of weekly slope changes; must exceed 104
+
+// Position NFT metadata; the URI base leaves room for the query string
+// appended per position
+pub const POSITION_NAME_MAX_LEN: usize = 32;
+pub const POSITION_SYMBOL_MAX_LEN: usize = 10;
+pub const POSITION_URI_BASE_MAX_LEN: usize = 96;
+
+// SPL Governance's account type tags for realms
+pub const REALM_V1_ACCOUNT_TYPE: u8 = 1;
+pub const REALM_V2_ACCOUNT_TYPE: u8 = 16;
+
+// Carried by every event; bumped whenever an event's fields change
+pub const EVENT_SCHEMA_VERSION: u8 = 2;
 
 #[program]
 pub mod staking_program {
     use super::*;
 
-    pub fn initialize_stake_pool(ctx: Context<InitializeStakePool>, stake_rate: u64) -> Result<()> {
+    pub fn initialize_stake_pool(
+        ctx: Context<InitializeStakePool>,
+        lock_tiers: Vec<LockTier>,
+        unbonding_period: i64,
+        early_exit_penalty: PenaltyConfig,
+    ) -> Result<()> {
+        check_mint_extensions(&ctx.accounts.token_mint)?;
+        check_mint_extensions(&ctx.accounts.reward_mint)?;
+
+        let now = Clock::get()?.unix_timestamp;
         let pool = &mut ctx.accounts.stake_pool;
         pool.authority = ctx.accounts.authority.key();
+        pool.pending_authority = Pubkey::default();
         pool.token_mint = ctx.accounts.token_mint.key();
+        pool.stake_vault = ctx.accounts.stake_vault.key();
         pool.total_staked = 0;
-        pool.stake_rate = stake_rate;
+        pool.total_effective_stake = 0;
+        pool.set_lock_tiers(&lock_tiers)?;
+        pool.set_unbonding_period(unbonding_period)?;
+        pool.total_unbonding = 0;
+        pool.voting_power.last_checkpoint = now;
         pool.bump = ctx.bumps.stake_pool;
+
+        // The primary stream stays idle until its first campaign is queued
+        pool.reward_stream_count = 1;
+        pool.reward_streams[0] = RewardStream::new(
+            ctx.accounts.reward_mint.key(),
+            ctx.accounts.reward_vault.key(),
+            now,
+        );
+
+        // Validated last: redistribution depends on stream 0's mint
+        pool.set_early_exit_penalty(early_exit_penalty)?;
+        check_treasury_account(pool, &ctx.accounts.treasury)?;
+
+        emit!(PoolInitialized {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            authority: pool.authority,
+            token_mint: pool.token_mint,
+            reward_mint: ctx.accounts.reward_mint.key(),
+            reward_vault: ctx.accounts.reward_vault.key(),
+            unbonding_period,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // Changes any subset of the pool's settings. Existing positions keep the
+    // lock and multiplier they were opened with; requests already in cooldown
+    // keep their end time. Emission rates are changed with set_reward_rate.
+    pub fn update_pool_config(ctx: Context<UpdatePoolConfig>, update: PoolConfigUpdate) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let pool = &mut ctx.accounts.stake_pool;
+
+        // Close out accrual under the old settings first
+        pool.update_rewards(now)?;
+
+        if let Some(lock_tiers) = &update.lock_tiers {
+            pool.set_lock_tiers(lock_tiers)?;
+        }
+        if let Some(unbonding_period) = update.unbonding_period {
+            pool.set_unbonding_period(unbonding_period)?;
+        }
+        if let Some(early_exit_penalty) = update.early_exit_penalty {
+            pool.set_early_exit_penalty(early_exit_penalty)?;
+            check_treasury_account(pool, &ctx.accounts.treasury)?;
+        }
+        let max_total_staked = update.max_total_staked.unwrap_or(pool.max_total_staked);
+        let max_stake_per_user = update.max_stake_per_user.unwrap_or(pool.max_stake_per_user);
+        let min_stake_amount = update.min_stake_amount.unwrap_or(pool.min_stake_amount);
+        pool.set_stake_limits(max_total_staked, max_stake_per_user, min_stake_amount)?;
+        // The fee is taken when rewards are paid, so a new rate also applies to
+        // rewards accrued before it and not yet claimed
+        let reward_fee_bps = update.reward_fee_bps.unwrap_or(pool.reward_fee_bps);
+        let fee_recipient = update.fee_recipient.unwrap_or(pool.fee_recipient);
+        pool.set_reward_fee(reward_fee_bps, fee_recipient)?;
+
+        emit!(ConfigUpdated {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            authority: pool.authority,
+            update,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // First half of a handover: the proposed key takes over once it accepts
+    pub fn propose_authority(ctx: Context<ManageAuthority>, new_authority: Pubkey) -> Result<()> {
+        require!(
+            new_authority != Pubkey::default(),
+            StakingError::InvalidAuthority
+        );
+        let pool = &mut ctx.accounts.stake_pool;
+        pool.pending_authority = new_authority;
+
+        emit!(AuthorityTransferProposed {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            authority: pool.authority,
+            pending_authority: new_authority,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let previous_authority = pool.authority;
+        pool.authority = pool.pending_authority;
+        pool.pending_authority = Pubkey::default();
+
+        emit!(AuthorityTransferred {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            previous_authority,
+            new_authority: pool.authority,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_authority_transfer(ctx: Context<ManageAuthority>) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        require!(
+            pool.pending_authority != Pubkey::default(),
+            StakingError::NoPendingAuthority
+        );
+        let pending_authority = pool.pending_authority;
+        pool.pending_authority = Pubkey::default();
+
+        emit!(AuthorityTransferCancelled {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            authority: pool.authority,
+            pending_authority,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    // Leaves the pool without an authority; no signer can match the default key,
+    // so every authority-gated instruction is closed for good
+    pub fn renounce_authority(ctx: Context<ManageAuthority>) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let previous_authority = pool.authority;
+        pool.authority = Pubkey::default();
+        pool.pending_authority = Pubkey::default();
+
+        emit!(AuthorityRenounced {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            previous_authority,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    // Stops new stakes and reward payouts; unstaking stays open
+    pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        require!(!pool.emergency_mode, StakingError::EmergencyMode);
+        pool.paused = paused;
+
+        emit!(PauseUpdated {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            paused,
+            emergency_mode: false,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    // One-way switch for when the reward math cannot be trusted: the pool stays
+    // paused and emergency_withdraw becomes available to every position
+    pub fn enable_emergency_mode(ctx: Context<SetPaused>) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        pool.paused = true;
+        pool.emergency_mode = true;
+
+        emit!(PauseUpdated {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            paused: true,
+            emergency_mode: true,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    // Restricts staking to wallets in the Merkle tree with this root; an
+    // all-zero root opens the pool to everyone again
+    pub fn set_allowlist_root(ctx: Context<SetAllowlistRoot>, root: [u8; 32]) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        pool.allowlist_root = root;
+
+        emit!(AllowlistRootUpdated {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            allowlist_root: root,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    pub fn add_reward_stream(ctx: Context<AddRewardStream>) -> Result<()> {
+        check_mint_extensions(&ctx.accounts.reward_mint)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let pool = &mut ctx.accounts.stake_pool;
+
+        require!(
+            (pool.reward_stream_count as usize) < MAX_REWARD_STREAMS,
+            StakingError::TooManyRewardStreams
+        );
+        require!(!pool.is_liquid(), StakingError::LiquidPoolRewards);
+
+        // Bring existing streams up to date so the new one starts from a clean state
+        pool.update_rewards(now)?;
+
+        let index = pool.reward_stream_count as usize;
+        pool.reward_streams[index] = RewardStream::new(
+            ctx.accounts.reward_mint.key(),
+            ctx.accounts.reward_vault.key(),
+            now,
+        );
+        pool.reward_stream_count += 1;
+
+        emit!(RewardStreamAdded {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            stream_index: index as u8,
+            reward_mint: ctx.accounts.reward_mint.key(),
+            reward_vault: ctx.accounts.reward_vault.key(),
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    pub fn queue_reward_campaign(
+        ctx: Context<QueueRewardCampaign>,
+        stream_index: u8,
+        budget: u64,
+        reward_start: i64,
+        reward_end: i64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let pool = &mut ctx.accounts.stake_pool;
+        // Only what reaches the vault after transfer fees can be emitted
+        let campaign = RewardCampaign {
+            budget: amount_after_fee(&ctx.accounts.reward_mint, budget)?,
+            start: reward_start,
+            end: reward_end,
+        };
+
+        // Settle up to now, which also rolls over any campaign that already ended
+        pool.update_rewards(now)?;
+
+        let stream = pool.stream_mut(stream_index)?;
+        require_keys_eq!(
+            ctx.accounts.reward_vault.key(),
+            stream.reward_vault,
+            StakingError::InvalidRewardVault
+        );
+        let started = stream.queue_campaign(campaign, now)?;
+
+        // The whole budget is escrowed up front
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.funder_token_account.to_account_info(),
+            mint: ctx.accounts.reward_mint.to_account_info(),
+            to: ctx.accounts.reward_vault.to_account_info(),
+            authority: ctx.accounts.authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_interface::transfer_checked(cpi_ctx, budget, ctx.accounts.reward_mint.decimals)?;
+
+        emit!(RewardCampaignQueued {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: ctx.accounts.stake_pool.key(),
+            stream_index,
+            budget: campaign.budget,
+            reward_rate: campaign.rate()?,
+            start: reward_start,
+            end: reward_end,
+            started,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // Changes the emission rate of a stream's running or scheduled campaign.
+    // Accrual is settled at the old rate first; the unspent budget is kept and
+    // the campaign ends earlier or later so it is spent at the new rate.
+    pub fn set_reward_rate(
+        ctx: Context<SetRewardRate>,
+        stream_index: u8,
+        reward_rate: u64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let pool = &mut ctx.accounts.stake_pool;
+        pool.update_rewards(now)?;
+
+        let stream = pool.stream_mut(stream_index)?;
+        stream.set_rate(reward_rate, now)?;
+
+        emit!(RewardRateUpdated {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            stream_index,
+            reward_rate,
+            reward_end: pool.reward_streams[stream_index as usize].reward_end,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // Returns a stream's unallocated tokens to the authority: what was emitted
+    // while nobody was staked, and what budgets left over after division into
+    // a whole per-second rate. Nothing any staker has earned can be taken.
+    pub fn reclaim_rewards(ctx: Context<ReclaimRewards>, stream_index: u8) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let pool = &mut ctx.accounts.stake_pool;
+        pool.update_rewards(now)?;
+
+        let stream = pool.stream_mut(stream_index)?;
+        require_keys_eq!(
+            ctx.accounts.reward_vault.key(),
+            stream.reward_vault,
+            StakingError::InvalidRewardVault
+        );
+        let amount = stream.unallocated;
+        require!(amount > 0, StakingError::NothingToReclaim);
+        stream.unallocated = 0;
+
+        transfer_from_pool(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.destination,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.token_program,
+            amount,
+        )?;
+
+        emit!(RewardsReclaimed {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: ctx.accounts.stake_pool.key(),
+            stream_index,
+            amount,
+            timestamp: now,
+        });
         Ok(())
     }
 
-    pub fn stake_tokens(ctx: Context<StakeTokens>, amount: u64) -> Result<()> {
+    pub fn stake_tokens(
+        ctx: Context<StakeTokens>,
+        amount: u64,
+        lock_tier: u8,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
         let pool = &mut ctx.accounts.stake_pool;
         let user_stake = &mut ctx.accounts.user_stake;
+        require!(!pool.paused, StakingError::PoolPaused);
+        require!(!pool.position_nfts, StakingError::NftPositionsOnly);
+        require!(!pool.is_liquid(), StakingError::LiquidStakesOnly);
+        let tier = pool.lock_tier(lock_tier)?;
+
+        // Settle rewards earned on the existing position before it changes
+        pool.checkpoint(user_stake, now)?;
+
+        // Transfer-fee mints deliver less than `amount`; only that is staked
+        let received = amount_after_fee(&ctx.accounts.token_mint, amount)?;
+        pool.check_stake_limits(user_stake.amount, received)?;
+        // Unbonding stake counts too, since cancel_unstake can restore it
+        pool.check_allowlist(
+            &ctx.accounts.user_authority.key(),
+            allowlist_proof.as_ref(),
+            user_stake.principal()?,
+            received,
+        )?;
 
         // Transfer tokens from user to the staking program
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: ctx.accounts.user_token_account.to_account_info(),
+            mint: ctx.accounts.token_mint.to_account_info(),
             to: ctx.accounts.pool_token_account.to_account_info(),
             authority: ctx.accounts.user_authority.to_account_info(),
         };
-        let cpi_program = ctx.accounts.token_program.key();
+        let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, amount)?;
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.token_mint.decimals)?;
 
-        // Update pool state
-        pool.total_staked += amount;
-        
-        // Update user stake record
-        user_stake.amount = amount;
-        user_stake.staked_at = Clock::get()?.unix_timestamp;
-        user_stake.reward_debt = 0;
+        user_stake.owner = ctx.accounts.user_authority.key();
+        user_stake.bump = ctx.bumps.user_stake;
+        pool.deposit(user_stake, tier, received, now)?;
 
+
+        emit_staked(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.user_stake,
+            ctx.accounts.user_authority.key(),
+            received,
+            now,
+        );
         Ok(())
     }
 
-    pub fn unstake_tokens(ctx: Context<UnstakeTokens>, amount: u64) -> Result<()> {
+    // Stakes into a new position represented by an NFT minted to the caller.
+    // Whoever holds the NFT controls the position and its accrued rewards.
+    // The per-user cap, and on allowlisted pools the wallet's cap, bound
+    // everything the wallet has opened.
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        amount: u64,
+        lock_tier: u8,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
         let pool = &mut ctx.accounts.stake_pool;
         let user_stake = &mut ctx.accounts.user_stake;
 
-        // Check if user has enough staked tokens
-        require!(user_stake.amount >= amount, StakingError::InsufficientStake);
+        require!(!pool.paused, StakingError::PoolPaused);
+        require!(pool.position_nfts, StakingError::NotNftPool);
+        require!(amount > 0, StakingError::InvalidAmount);
+        let received = amount_after_fee(&ctx.accounts.token_mint, amount)?;
+        let wallet_stake = &mut ctx.accounts.wallet_stake;
+        pool.check_stake_limits(wallet_stake.opened, received)?;
+        pool.check_allowlist(
+            &ctx.accounts.user_authority.key(),
+            allowlist_proof.as_ref(),
+            wallet_stake.opened,
+            received,
+        )?;
+        wallet_stake.opened = wallet_stake
+            .opened
+            .checked_add(received)
+            .ok_or(StakingError::MathOverflow)?;
+        wallet_stake.bump = ctx.bumps.wallet_stake;
+        let tier = pool.lock_tier(lock_tier)?;
+
+        // Start the new position at the current accumulator values
+        pool.update_rewards(now)?;
+        user_stake.settle(pool)?;
+
+        user_stake.owner = ctx.accounts.position_mint.key();
+        user_stake.nft_position = true;
+        user_stake.bump = ctx.bumps.user_stake;
+        pool.deposit(user_stake, tier, received, now)?;
+        let lock_end = user_stake.lock_end;
 
-        // Calculate rewards (simplified)
-        let reward = calculate_reward(pool, user_stake)?;
-        
-        // Transfer rewards to user
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_token_account.to_account_info(),
-            to: ctx.accounts.user_token_account.to_account_info(),
-            authority: ctx.accounts.pool_authority.to_account_info(),
+        // Transfer tokens from user to the staking program
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.user_token_account.to_account_info(),
+            mint: ctx.accounts.token_mint.to_account_info(),
+            to: ctx.accounts.pool_token_account.to_account_info(),
+            authority: ctx.accounts.user_authority.to_account_info(),
         };
-        let cpi_program = ctx.accounts.token_program.key();
+        let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, amount + reward)?;
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.token_mint.decimals)?;
+
+        let pool = &ctx.accounts.stake_pool;
+        let seeds = pool.signer_seeds();
+        let signer = &[&seeds[..]];
+
+        // Mint the position NFT to the caller
+        let cpi_accounts = MintTo {
+            mint: ctx.accounts.position_mint.to_account_info(),
+            to: ctx.accounts.position_token_account.to_account_info(),
+            authority: pool.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::mint_to(cpi_ctx, 1)?;
+
+        // Describe the position in its metadata
+        let config = &ctx.accounts.position_config;
+        let data = DataV2 {
+            name: config.name.clone(),
+            symbol: config.symbol.clone(),
+            uri: format!(
+                "{}?pool={}&amount={}&lock_end={}",
+                config.uri,
+                pool.key(),
+                received,
+                lock_end
+            ),
+            seller_fee_basis_points: 0,
+            creators: None,
+            collection: None,
+            uses: None,
+        };
+        let cpi_accounts = metadata::CreateMetadataAccountsV3 {
+            metadata: ctx.accounts.position_metadata.to_account_info(),
+            mint: ctx.accounts.position_mint.to_account_info(),
+            mint_authority: pool.to_account_info(),
+            payer: ctx.accounts.user_authority.to_account_info(),
+            update_authority: pool.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            rent: ctx.accounts.rent.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.metadata_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        metadata::create_metadata_accounts_v3(cpi_ctx, data, false, true, None)?;
+
+        emit_staked(
+            pool,
+            &ctx.accounts.user_stake,
+            ctx.accounts.user_authority.key(),
+            received,
+            now,
+        );
+
+        // Fix the supply at one
+        let cpi_accounts = SetAuthority {
+            current_authority: pool.to_account_info(),
+            account_or_mint: ctx.accounts.position_mint.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::set_authority(cpi_ctx, AuthorityType::MintTokens, None)?;
+
+        Ok(())
+    }
+
+    pub fn unstake_tokens<'info>(
+        ctx: Context<'_, '_, 'info, 'info, UnstakeTokens<'info>>,
+        amount: u64,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        // Pools with a cooldown go through request_unstake / withdraw_unstaked
+        require!(pool.unbonding_period == 0, StakingError::UnbondingRequired);
+
+        // Settle rewards up to now; they are paid out with the principal
+        let now = Clock::get()?.unix_timestamp;
+        pool.checkpoint(user_stake, now)?;
+        let penalty = pool.withdraw(user_stake, amount, now)?;
+
+        // Redistributed penalties go to the stakers that remain, via stream 0,
+        // less any fee taken on the way into its vault. With nobody left to
+        // share it, the penalty is burned rather than stranded in the vault.
+        let mut destination = pool.early_exit_penalty.destination;
+        if destination == PenaltyDestination::Redistribute {
+            let total_effective_stake = pool.total_effective_stake;
+            if total_effective_stake == 0 {
+                destination = PenaltyDestination::Burn;
+            } else if penalty > 0 {
+                let redistributed = amount_after_fee(&ctx.accounts.token_mint, penalty)?;
+                pool.reward_streams[0].distribute(redistributed, total_effective_stake)?;
+            }
+        }
+
+
+        // Return principal from the stake vault
+        transfer_from_pool(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.pool_token_account,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            amount - penalty,
+        )?;
+        if penalty > 0 {
+            route_early_exit_penalty(ctx.accounts, destination, penalty)?;
+        }
+
+        // Pay rewards from every stream's vault. A pause still lets principal
+        // leave, but rewards stay pending until the pool is unpaused.
+        let (paid, fees) = if ctx.accounts.stake_pool.paused {
+            Default::default()
+        } else {
+            pay_rewards(
+                &ctx.accounts.stake_pool,
+                &mut ctx.accounts.user_stake,
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.reward_mint,
+                &ctx.accounts.user_reward_account,
+                &ctx.accounts.fee_account,
+                ctx.remaining_accounts,
+                &ctx.accounts.token_program,
+            )?
+        };
+        ctx.accounts.stake_pool.record_fees(&fees)?;
+
+        let pool = &ctx.accounts.stake_pool;
+        let user_stake = &ctx.accounts.user_stake;
+        emit!(Unstaked {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            user_stake: user_stake.key(),
+            owner: ctx.accounts.user_authority.key(),
+            amount,
+            penalty,
+            user_amount: user_stake.amount,
+            total_staked: pool.total_staked,
+            timestamp: now,
+        });
+        emit_rewards_claimed(pool, user_stake, ctx.accounts.user_authority.key(), paid, fees, now);
+        Ok(())
+    }
+
+    pub fn request_unstake(ctx: Context<RequestUnstake>, amount: u64) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        require!(amount > 0, StakingError::InvalidAmount);
+
+        // Settle first: the amount stops earning as soon as it is queued
+        let now = Clock::get()?.unix_timestamp;
+        pool.checkpoint(user_stake, now)?;
+
+        require!(user_stake.amount >= amount, StakingError::InsufficientStake);
+        require!(now >= user_stake.lock_end, StakingError::StakeLocked);
 
-        // Update pool state
         pool.total_staked -= amount;
-        
-        // Update user stake record
+        pool.total_unbonding = pool
+            .total_unbonding
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        // Further requests join the queue and restart its cooldown
         user_stake.amount -= amount;
+        user_stake.unbonding_amount = user_stake
+            .unbonding_amount
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        user_stake.cooldown_ends_at = now
+            .checked_add(pool.unbonding_period)
+            .ok_or(StakingError::MathOverflow)?;
+        pool.sync_effective_stake(user_stake, now)?;
+
+
+        let pool = &ctx.accounts.stake_pool;
+        let user_stake = &ctx.accounts.user_stake;
+        emit!(UnstakeRequested {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            user_stake: user_stake.key(),
+            owner: ctx.accounts.user_authority.key(),
+            amount,
+            user_amount: user_stake.amount,
+            unbonding_amount: user_stake.unbonding_amount,
+            cooldown_ends_at: user_stake.cooldown_ends_at,
+            total_staked: pool.total_staked,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    pub fn withdraw_unstaked(ctx: Context<WithdrawUnstaked>) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        let amount = user_stake.unbonding_amount;
+        require!(amount > 0, StakingError::NothingToWithdraw);
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= user_stake.cooldown_ends_at, StakingError::CooldownActive);
+
+        pool.total_unbonding -= amount;
+        user_stake.unbonding_amount = 0;
+        user_stake.cooldown_ends_at = 0;
+
+        // Release the principal from the stake vault
+        transfer_from_pool(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.pool_token_account,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            amount,
+        )?;
+
+        emit!(UnstakeWithdrawn {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: ctx.accounts.stake_pool.key(),
+            user_stake: ctx.accounts.user_stake.key(),
+            owner: ctx.accounts.user_authority.key(),
+            amount,
+            total_unbonding: ctx.accounts.stake_pool.total_unbonding,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_unstake(ctx: Context<CancelUnstake>) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        let amount = user_stake.unbonding_amount;
+        require!(amount > 0, StakingError::NothingToWithdraw);
+
+        // Settle before the restaked amount starts earning again
+        let now = Clock::get()?.unix_timestamp;
+        pool.checkpoint(user_stake, now)?;
+
+        // The caps may have filled up, or been lowered, since the request
+        pool.check_stake_caps(user_stake.amount, amount)?;
+
+        pool.total_unbonding -= amount;
+        pool.total_staked = pool
+            .total_staked
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        user_stake.unbonding_amount = 0;
+        user_stake.cooldown_ends_at = 0;
+        user_stake.amount = user_stake
+            .amount
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        pool.sync_effective_stake(user_stake, now)?;
+
+
+        emit!(UnstakeCancelled {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: ctx.accounts.stake_pool.key(),
+            user_stake: ctx.accounts.user_stake.key(),
+            owner: ctx.accounts.user_authority.key(),
+            amount,
+            user_amount: ctx.accounts.user_stake.amount,
+            total_staked: ctx.accounts.stake_pool.total_staked,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // Returns a position's principal, staked and unbonding, ignoring locks and
+    // forfeiting every pending reward. Nothing here touches the accumulators,
+    // and the lock leaves the voting power total only if that math still
+    // holds, so it works even if they are broken.
+    pub fn emergency_withdraw(ctx: Context<EmergencyWithdraw>) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        require!(pool.emergency_mode, StakingError::NotEmergencyMode);
+
+        let amount = user_stake.principal()?;
+        require!(amount > 0, StakingError::NothingToWithdraw);
+
+        pool.total_staked = pool.total_staked.saturating_sub(user_stake.amount);
+        pool.total_effective_stake = pool
+            .total_effective_stake
+            .saturating_sub(user_stake.effective_amount);
+        pool.total_unbonding = pool.total_unbonding.saturating_sub(user_stake.unbonding_amount);
+
+        let now = Clock::get()?.unix_timestamp;
+        let mut voting_power = pool.voting_power;
+        let removed = voting_power.checkpoint(now).and_then(|_| {
+            voting_power.replace_lock(
+                (user_stake.voting_amount, user_stake.voting_lock_end),
+                (0, 0),
+                now,
+            )
+        });
+        if removed.is_ok() {
+            pool.voting_power = voting_power;
+        }
+
+        user_stake.amount = 0;
+        user_stake.effective_amount = 0;
+        user_stake.unbonding_amount = 0;
+        user_stake.cooldown_ends_at = 0;
+        user_stake.voting_amount = 0;
+        user_stake.voting_lock_end = 0;
+        user_stake.rewards = [UserReward::default(); MAX_REWARD_STREAMS];
+
+        transfer_from_pool(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.pool_token_account,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            amount,
+        )?;
+
+        emit!(EmergencyWithdrawn {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: ctx.accounts.stake_pool.key(),
+            user_stake: ctx.accounts.user_stake.key(),
+            owner: ctx.accounts.user_authority.key(),
+            amount,
+            total_staked: ctx.accounts.stake_pool.total_staked,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    pub fn claim_rewards<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ClaimRewards<'info>>,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        require!(!pool.paused, StakingError::PoolPaused);
+
+        // Settle rewards up to now
+        let now = Clock::get()?.unix_timestamp;
+        pool.checkpoint(user_stake, now)?;
+
+        // Drop the boost if the lock has run out since the last interaction
+        pool.sync_effective_stake(user_stake, now)?;
+
+        // Transfer rewards to user from every stream's vault
+        let (paid, fees) = pay_rewards(
+            &ctx.accounts.stake_pool,
+            &mut ctx.accounts.user_stake,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.user_reward_account,
+            &ctx.accounts.fee_account,
+            ctx.remaining_accounts,
+            &ctx.accounts.token_program,
+        )?;
+        ctx.accounts.stake_pool.record_fees(&fees)?;
+
+        emit_rewards_claimed(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.user_stake,
+            ctx.accounts.user_authority.key(),
+            paid,
+            fees,
+            now,
+        );
+        Ok(())
+    }
+
+    pub fn compound(ctx: Context<Compound>, allowlist_proof: Option<AllowlistProof>) -> Result<()> {
+        require!(!ctx.accounts.stake_pool.paused, StakingError::PoolPaused);
+
+        let now = Clock::get()?.unix_timestamp;
+        let (reward, fee, restaked) = ctx.accounts.stake_pool.compound(
+            &mut ctx.accounts.user_stake,
+            allowlist_proof.as_ref(),
+            &ctx.accounts.token_mint,
+            now,
+        )?;
+
+        pay_compound_fee(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.fee_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            fee,
+        )?;
+        // Move the compounded rewards from the reward vault into the stake vault
+        transfer_from_pool(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.pool_token_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            reward,
+        )?;
+
+        emit_compounded(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.user_stake,
+            reward,
+            fee,
+            restaked,
+            now,
+        );
+        Ok(())
+    }
+
+    pub fn set_auto_compound(ctx: Context<SetAutoCompound>, enabled: bool) -> Result<()> {
+        ctx.accounts.user_stake.auto_compound = enabled;
+
+        emit!(AutoCompoundUpdated {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: ctx.accounts.stake_pool.key(),
+            user_stake: ctx.accounts.user_stake.key(),
+            enabled,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    // Permissionless crank for positions that opted into auto-compounding
+    // `allowlist_proof` is the position owner's, on allowlisted pools
+    pub fn compound_for(
+        ctx: Context<CompoundFor>,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.stake_pool.paused, StakingError::PoolPaused);
+        require!(
+            ctx.accounts.user_stake.auto_compound,
+            StakingError::AutoCompoundDisabled
+        );
+
+        let now = Clock::get()?.unix_timestamp;
+        let (reward, fee, restaked) = ctx.accounts.stake_pool.compound(
+            &mut ctx.accounts.user_stake,
+            allowlist_proof.as_ref(),
+            &ctx.accounts.token_mint,
+            now,
+        )?;
+
+        pay_compound_fee(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.fee_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            fee,
+        )?;
+        transfer_from_pool(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.pool_token_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            reward,
+        )?;
 
+        emit_compounded(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.user_stake,
+            reward,
+            fee,
+            restaked,
+            now,
+        );
         Ok(())
     }
 
-    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+    // Permissionless crank that drops the boost of a lock that has ended, so the
+    // position stops earning the boosted share as soon as the crank runs rather
+    // than whenever its owner next acts
+    pub fn expire_lock(ctx: Context<ExpireLock>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
         let pool = &mut ctx.accounts.stake_pool;
         let user_stake = &mut ctx.accounts.user_stake;
+        require!(now >= user_stake.lock_end, StakingError::StakeLocked);
+        require!(
+            user_stake.multiplier_bps as u64 > BPS_DENOMINATOR,
+            StakingError::LockNotBoosted
+        );
 
-        // Calculate rewards
-        let reward = calculate_reward(pool, user_stake)?;
-        
-        // Transfer rewards to user
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_token_account.to_account_info(),
-            to: ctx.accounts.user_token_account.to_account_info(),
-            authority: ctx.accounts.pool_authority.to_account_info(),
+        pool.checkpoint(user_stake, now)?;
+        pool.sync_effective_stake(user_stake, now)?;
+
+        emit!(LockExpired {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            user_stake: user_stake.key(),
+            effective_amount: user_stake.effective_amount,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // Switches an empty pool to liquid staking: from now on stakes go through
+    // stake_liquid and are held as receipt tokens, which are ordinary tokens
+    // and move freely. A receipt is a share of the pool's stake; stream 0's
+    // rewards are restaked into the pool as they are harvested, so each
+    // receipt redeems for more over time.
+    pub fn enable_receipt_mint(ctx: Context<EnableReceiptMint>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let pool = &mut ctx.accounts.stake_pool;
+
+        require!(
+            pool.total_staked == 0 && pool.total_unbonding == 0,
+            StakingError::PoolNotEmpty
+        );
+        require!(!pool.position_nfts, StakingError::PositionModeConflict);
+        // Locks and cooldowns belong to a holder, and receipts have none
+        require!(
+            pool.unbonding_period == 0
+                && pool.lock_tiers[..pool.lock_tier_count as usize]
+                    .iter()
+                    .all(|tier| tier.duration == 0),
+            StakingError::LiquidPoolLocks
+        );
+        // Rewards can only accrue into the redemption rate if they are paid
+        // in the token the receipts redeem for
+        require!(
+            pool.reward_stream_count == 1 && pool.reward_streams[0].reward_mint == pool.token_mint,
+            StakingError::LiquidPoolRewards
+        );
+
+        pool.update_rewards(now)?;
+        pool.liquid_reward_index = pool.reward_streams[0].reward_per_token_stored;
+        pool.receipt_mint = ctx.accounts.receipt_mint.key();
+
+        emit!(ReceiptMintEnabled {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            receipt_mint: pool.receipt_mint,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // Stakes into a liquid pool and mints receipts for the stake's share of the
+    // pool. On capped or allowlisted pools the wallet's caps bound everything
+    // it has deposited, since the receipts can change hands.
+    pub fn stake_liquid(
+        ctx: Context<StakeLiquid>,
+        amount: u64,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        require!(!ctx.accounts.stake_pool.paused, StakingError::PoolPaused);
+        require!(ctx.accounts.stake_pool.is_liquid(), StakingError::NotLiquidPool);
+        require!(amount > 0, StakingError::InvalidAmount);
+
+        // Rewards up to now belong to the receipts that already exist
+        harvest_liquid(
+            &mut ctx.accounts.stake_pool,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.pool_token_account,
+            &ctx.accounts.fee_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            now,
+        )?;
+
+        let pool = &mut ctx.accounts.stake_pool;
+        let received = amount_after_fee(&ctx.accounts.token_mint, amount)?;
+        let wallet_stake = &mut ctx.accounts.wallet_stake;
+        pool.check_stake_limits(wallet_stake.opened, received)?;
+        pool.check_allowlist(
+            &ctx.accounts.user_authority.key(),
+            allowlist_proof.as_ref(),
+            wallet_stake.opened,
+            received,
+        )?;
+        wallet_stake.opened = wallet_stake
+            .opened
+            .checked_add(received)
+            .ok_or(StakingError::MathOverflow)?;
+        wallet_stake.bump = ctx.bumps.wallet_stake;
+        let receipts = pool.deposit_liquid(received, ctx.accounts.receipt_mint.supply)?;
+
+        // Transfer tokens from user to the staking program
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.user_token_account.to_account_info(),
+            mint: ctx.accounts.token_mint.to_account_info(),
+            to: ctx.accounts.pool_token_account.to_account_info(),
+            authority: ctx.accounts.user_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.token_mint.decimals)?;
+
+        let pool = &ctx.accounts.stake_pool;
+        let seeds = pool.signer_seeds();
+        let signer = &[&seeds[..]];
+        let cpi_accounts = MintTo {
+            mint: ctx.accounts.receipt_mint.to_account_info(),
+            to: ctx.accounts.user_receipt_account.to_account_info(),
+            authority: pool.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::mint_to(cpi_ctx, receipts)?;
+
+        emit!(LiquidStaked {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            owner: ctx.accounts.user_authority.key(),
+            amount: received,
+            receipts,
+            total_staked: pool.total_staked,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // Burns receipts for their share of the pool's stake. Harvesting is skipped
+    // in emergency mode, and while the pool is paused the rewards harvested
+    // since the pause stay out of the redemption rate until it is unpaused.
+    pub fn unstake_liquid(ctx: Context<UnstakeLiquid>, receipts: u64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        require!(ctx.accounts.stake_pool.is_liquid(), StakingError::NotLiquidPool);
+
+        if !ctx.accounts.stake_pool.emergency_mode {
+            harvest_liquid(
+                &mut ctx.accounts.stake_pool,
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.pool_token_account,
+                &ctx.accounts.fee_account,
+                &ctx.accounts.token_mint,
+                &ctx.accounts.token_program,
+                now,
+            )?;
+        }
+        let amount = ctx
+            .accounts
+            .stake_pool
+            .redeem_liquid(receipts, ctx.accounts.receipt_mint.supply)?;
+
+        let cpi_accounts = Burn {
+            mint: ctx.accounts.receipt_mint.to_account_info(),
+            from: ctx.accounts.user_receipt_account.to_account_info(),
+            authority: ctx.accounts.user_authority.to_account_info(),
         };
-        let cpi_program = ctx.accounts.token_program.key();
+        let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, reward)?;
+        token_interface::burn(cpi_ctx, receipts)?;
+
+        transfer_from_pool(
+            &ctx.accounts.stake_pool,
+            &ctx.accounts.pool_token_account,
+            &ctx.accounts.user_token_account,
+            &ctx.accounts.token_mint,
+            &ctx.accounts.token_program,
+            amount,
+        )?;
+
+        emit!(LiquidUnstaked {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: ctx.accounts.stake_pool.key(),
+            owner: ctx.accounts.user_authority.key(),
+            receipts,
+            amount,
+            total_staked: ctx.accounts.stake_pool.total_staked,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    // Switches an empty pool to NFT positions: every stake goes through
+    // open_position and mints a position NFT described by this config
+    pub fn enable_position_nfts(
+        ctx: Context<EnablePositionNfts>,
+        name: String,
+        symbol: String,
+        uri: String,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+
+        require!(
+            pool.total_staked == 0 && pool.total_unbonding == 0,
+            StakingError::PoolNotEmpty
+        );
+        require!(!pool.is_liquid(), StakingError::PositionModeConflict);
+        require!(
+            name.len() <= POSITION_NAME_MAX_LEN
+                && symbol.len() <= POSITION_SYMBOL_MAX_LEN
+                && uri.len() <= POSITION_URI_BASE_MAX_LEN,
+            StakingError::PositionMetadataTooLong
+        );
+
+        pool.position_nfts = true;
+
+        let config = &mut ctx.accounts.position_config;
+        config.stake_pool = pool.key();
+        config.name = name;
+        config.symbol = symbol;
+        config.uri = uri;
+        config.bump = ctx.bumps.position_config;
+
+        emit!(PositionNftsEnabled {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: config.stake_pool,
+            position_config: config.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    // Voting power of a position at `timestamp`, computed from its current lock
+    pub fn get_voting_power(ctx: Context<GetVotingPower>, timestamp: i64) -> Result<u64> {
+        ctx.accounts.user_stake.voting_power_at(timestamp)
+    }
+
+    // Pool-wide voting power at `timestamp`, which cannot precede the last checkpoint
+    pub fn get_total_voting_power(ctx: Context<GetTotalVotingPower>, timestamp: i64) -> Result<u64> {
+        ctx.accounts.stake_pool.voting_power.total_at(timestamp)
+    }
+
+    // Links the pool to an SPL Governance realm whose governing mint is the
+    // pool's staking mint, so its voter weight records count in that realm.
+    // The realm authority signs, and attests with `voting_period` to the
+    // longest vote the realm runs: stake leaving the pool stays in its
+    // cooldown for at least that long, so it cannot vote again from another
+    // wallet while a proposal it voted on is open. The registrar PDA allows
+    // one pool per realm and mint.
+    pub fn create_registrar(ctx: Context<CreateRegistrar>, voting_period: i64) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        require!(
+            !pool.has_transferable_positions(),
+            StakingError::TransferablePositions
+        );
+        require!(
+            voting_period > 0 && pool.unbonding_period >= voting_period,
+            StakingError::UnbondingShorterThanVoting
+        );
 
-        // Update reward debt
-        user_stake.reward_debt += reward;
+        let realm = &ctx.accounts.realm;
+        require_keys_eq!(
+            *realm.owner,
+            ctx.accounts.governance_program.key(),
+            StakingError::InvalidRealm
+        );
+        let header = RealmHeader::deserialize(&mut &realm.try_borrow_data()?[..])
+            .map_err(|_| error!(StakingError::InvalidRealm))?;
+        require!(
+            header.account_type == REALM_V1_ACCOUNT_TYPE
+                || header.account_type == REALM_V2_ACCOUNT_TYPE,
+            StakingError::InvalidRealm
+        );
+        require!(
+            header.authority == Some(ctx.accounts.realm_authority.key()),
+            StakingError::InvalidRealmAuthority
+        );
+        require!(
+            header.community_mint == pool.token_mint
+                || header.config.council_mint == Some(pool.token_mint),
+            StakingError::InvalidGoverningMint
+        );
 
+        pool.realm = realm.key();
+        pool.realm_governing_token_mint = pool.token_mint;
+        pool.realm_voting_period = voting_period;
+
+        let registrar = &mut ctx.accounts.registrar;
+        registrar.stake_pool = pool.key();
+        registrar.governance_program = ctx.accounts.governance_program.key();
+        registrar.realm = pool.realm;
+        registrar.governing_token_mint = pool.realm_governing_token_mint;
+        registrar.bump = ctx.bumps.registrar;
+
+        emit!(RegistrarCreated {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: registrar.stake_pool,
+            registrar: registrar.key(),
+            realm: registrar.realm,
+            governing_token_mint: registrar.governing_token_mint,
+            voting_period,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    // Governance addin entry point: refreshes the caller's voter weight from the
+    // staked amount and the realm's max voter weight from the pool total. Both
+    // records expire in the current slot, so this must run in the same
+    // transaction as the governance instruction that reads them.
+    pub fn update_voter_weight_record(ctx: Context<UpdateVoterWeightRecord>) -> Result<()> {
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.stake_pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        require!(
+            pool.realm != Pubkey::default(),
+            StakingError::GovernanceNotConfigured
+        );
+        // A position that moved after voting could vote again from its new
+        // holder's record, so only pools with fixed owners get voter weight
+        require!(
+            !pool.has_transferable_positions(),
+            StakingError::TransferablePositions
+        );
+        // Emergency withdrawals skip the cooldown that keeps votes single
+        require!(!pool.emergency_mode, StakingError::EmergencyMode);
+
+        pool.checkpoint(user_stake, clock.unix_timestamp)?;
+
+        let record = &mut ctx.accounts.voter_weight_record;
+        record.realm = pool.realm;
+        record.governing_token_mint = pool.realm_governing_token_mint;
+        record.governing_token_owner = ctx.accounts.user_authority.key();
+        record.voter_weight = user_stake.amount;
+        record.voter_weight_expiry = Some(clock.slot);
+        record.weight_action = None;
+        record.weight_action_target = None;
+
+        let max_record = &mut ctx.accounts.max_voter_weight_record;
+        max_record.realm = pool.realm;
+        max_record.governing_token_mint = pool.realm_governing_token_mint;
+        max_record.max_voter_weight = pool.total_staked;
+        max_record.max_voter_weight_expiry = Some(clock.slot);
+
+        emit!(VoterWeightUpdated {
+            version: EVENT_SCHEMA_VERSION,
+            stake_pool: pool.key(),
+            user_stake: user_stake.key(),
+            owner: ctx.accounts.user_authority.key(),
+            voter_weight: user_stake.amount,
+            max_voter_weight: pool.total_staked,
+            expiry_slot: clock.slot,
+            timestamp: clock.unix_timestamp,
+        });
         Ok(())
     }
 }
@@ -112,21 +1368,214 @@ pub struct InitializeStakePool<'info> {
         bump
     )]
     pub stake_pool: Account<'info, StakePool>,
-    pub token_mint: Account<'info, Mint>,
-    #[account(mut)]
-    pub authority: Signer<'info>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
+    // Every mint of a pool belongs to the same token program
+    #[account(
+        constraint = *token_mint.to_account_info().owner == token_program.key()
+            @ StakingError::TokenProgramMismatch
+    )]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        constraint = *reward_mint.to_account_info().owner == token_program.key()
+            @ StakingError::TokenProgramMismatch
+    )]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = authority,
+        token::mint = reward_mint,
+        token::authority = stake_pool,
+        token::token_program = token_program,
+        seeds = [b"reward_vault", stake_pool.key().as_ref(), reward_mint.key().as_ref()],
+        bump
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    // Holds staked principal only, apart from every reward vault
+    #[account(
+        init,
+        payer = authority,
+        token::mint = token_mint,
+        token::authority = stake_pool,
+        token::token_program = token_program,
+        seeds = [b"stake_vault", stake_pool.key().as_ref()],
+        bump
+    )]
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
+    // Only needed when penalties go to a treasury
+    pub treasury: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePoolConfig<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    // Only needed when the update sends penalties to a treasury
+    pub treasury: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub authority: Signer<'info>,
+}
+
+// Shared by propose, cancel and renounce
+#[derive(Accounts)]
+pub struct ManageAuthority<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        constraint = stake_pool.pending_authority == new_authority.key()
+            @ StakingError::NotPendingAuthority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    pub new_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAllowlistRoot<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AddRewardStream<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        address = stake_pool.token_mint,
+        constraint = *token_mint.to_account_info().owner == token_program.key()
+            @ StakingError::TokenProgramMismatch
+    )]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        constraint = *reward_mint.to_account_info().owner == token_program.key()
+            @ StakingError::TokenProgramMismatch
+    )]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = authority,
+        token::mint = reward_mint,
+        token::authority = stake_pool,
+        token::token_program = token_program,
+        seeds = [b"reward_vault", stake_pool.key().as_ref(), reward_mint.key().as_ref()],
+        bump
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct SetRewardRate<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct QueueRewardCampaign<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = reward_vault.mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        constraint = funder_token_account.mint == reward_vault.mint
+    )]
+    pub funder_token_account: InterfaceAccount<'info, TokenAccount>,
+    pub authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ReclaimRewards<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = reward_vault.mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        constraint = destination.mint == reward_vault.mint
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+    pub authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
 pub struct StakeTokens<'info> {
     #[account(
         mut,
-        seeds = [b"stake_pool", token_mint.key().as_ref()],
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
         bump = stake_pool.bump
     )]
     pub stake_pool: Account<'info, StakePool>,
+    #[account(address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
     #[account(
         init_if_needed,
         payer = user_authority,
@@ -135,116 +1584,2378 @@ pub struct StakeTokens<'info> {
         bump
     )]
     pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_token_account.mint == stake_pool.token_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct OpenPosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        seeds = [b"position_nft_config", stake_pool.key().as_ref()],
+        bump = position_config.bump
+    )]
+    pub position_config: Account<'info, PositionNftConfig>,
+    #[account(
+        init,
+        payer = user_authority,
+        mint::decimals = 0,
+        mint::authority = stake_pool
+    )]
+    pub position_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + std::mem::size_of::<UserStake>(),
+        seeds = [b"user_stake", position_mint.key().as_ref(), stake_pool.key().as_ref()],
+        bump
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(
+        init_if_needed,
+        payer = user_authority,
+        space = 8 + WalletStake::INIT_SPACE,
+        seeds = [b"wallet_stake", user_authority.key().as_ref(), stake_pool.key().as_ref()],
+        bump
+    )]
+    pub wallet_stake: Account<'info, WalletStake>,
+    #[account(
+        init,
+        payer = user_authority,
+        associated_token::mint = position_mint,
+        associated_token::authority = user_authority
+    )]
+    pub position_token_account: InterfaceAccount<'info, TokenAccount>,
+    /// CHECK: metadata PDA, created and validated by the metadata program
     #[account(
         mut,
-        constraint = pool_token_account.mint == stake_pool.token_mint
+        seeds = [b"metadata", metadata_program.key().as_ref(), position_mint.key().as_ref()],
+        seeds::program = metadata_program.key(),
+        bump
     )]
-    pub pool_token_account: Account<'info, TokenAccount>,
+    pub position_metadata: UncheckedAccount<'info>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
+        mut,
         constraint = user_token_account.mint == stake_pool.token_mint
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut)]
     pub user_authority: Signer<'info>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub metadata_program: Program<'info, Metadata>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
 }
 
+// Streams beyond the first are paid through remaining accounts, passed as
+// (reward_vault, reward_mint, user_reward_account) triples in stream order,
+// each followed by the fee recipient's account for that mint when the pool
+// charges a reward fee
 #[derive(Accounts)]
 pub struct UnstakeTokens<'info> {
     #[account(
         mut,
-        seeds = [b"stake_pool", token_mint.key().as_ref()],
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
         bump = stake_pool.bump
     )]
     pub stake_pool: Account<'info, StakePool>,
+    // Writable for pools that burn early-exit penalties
+    #[account(mut, address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(address = stake_pool.reward_streams[0].reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
     #[account(
         mut,
-        seeds = [b"user_stake", user_authority.key().as_ref(), stake_pool.key().as_ref()],
-        bump = user_stake.bump
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
     )]
     pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
         mut,
-        constraint = pool_token_account.mint == stake_pool.token_mint
+        constraint = user_token_account.mint == stake_pool.token_mint
     )]
-    pub pool_token_account: Account<'info, TokenAccount>,
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, address = stake_pool.reward_streams[0].reward_vault)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
     #[account(
-        constraint = user_token_account.mint == stake_pool.token_mint
+        mut,
+        constraint = user_reward_account.mint == stake_pool.reward_streams[0].reward_mint
+    )]
+    pub user_reward_account: InterfaceAccount<'info, TokenAccount>,
+    // Only needed for early exits from pools that send penalties to a treasury
+    #[account(mut, address = stake_pool.early_exit_penalty.treasury)]
+    pub treasury: Option<InterfaceAccount<'info, TokenAccount>>,
+    // Pools charging a reward fee only: the fee recipient's stream 0 account
+    #[account(mut)]
+    pub fee_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RequestUnstake<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub user_stake: Account<'info, UserStake>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
     pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawUnstaked<'info> {
     #[account(
         mut,
-        seeds = [b"stake_pool", stake_pool.token_mint.key().as_ref()],
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
         bump = stake_pool.bump
     )]
-    pub pool_authority: Account<'info, StakePool>,
-    pub token_program: Program<'info, Token>,
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_token_account.mint == stake_pool.token_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
-pub struct ClaimRewards<'info> {
+pub struct CancelUnstake<'info> {
     #[account(
         mut,
-        seeds = [b"stake_pool", token_mint.key().as_ref()],
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
         bump = stake_pool.bump
     )]
     pub stake_pool: Account<'info, StakePool>,
     #[account(
         mut,
-        seeds = [b"user_stake", user_authority.key().as_ref(), stake_pool.key().as_ref()],
-        bump = user_stake.bump
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
     )]
     pub user_stake: Account<'info, UserStake>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyWithdraw<'info> {
     #[account(
         mut,
-        constraint = pool_token_account.mint == stake_pool.token_mint
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
     )]
-    pub pool_token_account: Account<'info, TokenAccount>,
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
+        mut,
         constraint = user_token_account.mint == stake_pool.token_mint
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
     pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// Same remaining-accounts layout as `UnstakeTokens`
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
     #[account(
         mut,
-        seeds = [b"stake_pool", stake_pool.token_mint.key().as_ref()],
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
         bump = stake_pool.bump
     )]
-    pub pool_authority: Account<'info, StakePool>,
-    pub token_program: Program<'info, Token>,
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(address = stake_pool.reward_streams[0].reward_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = stake_pool.reward_streams[0].reward_vault)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_reward_account.mint == stake_pool.reward_streams[0].reward_mint
+    )]
+    pub user_reward_account: InterfaceAccount<'info, TokenAccount>,
+    // Pools charging a reward fee only: the fee recipient's stream 0 account
+    #[account(mut)]
+    pub fee_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
-#[account]
-pub struct StakePool {
-    pub authority: Pubkey,
-    pub token_mint: Pubkey,
-    pub total_staked: u64,
-    pub stake_rate: u64, // Rewards per second
-    pub bump: u8,
+#[derive(Accounts)]
+pub struct Compound<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, address = stake_pool.reward_streams[0].reward_vault)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    // Pools charging a reward fee only: the fee recipient's staking-mint account
+    #[account(mut)]
+    pub fee_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
-#[account]
-pub struct UserStake {
-    pub amount: u64,
-    pub staked_at: i64,
-    pub reward_debt: u64,
-    pub bump: u8,
+#[derive(Accounts)]
+pub struct SetAutoCompound<'info> {
+    #[account(
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub user_authority: Signer<'info>,
+}
+
+// Same as `Compound`, but anyone may sign
+#[derive(Accounts)]
+pub struct CompoundFor<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, address = stake_pool.reward_streams[0].reward_vault)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    // Pools charging a reward fee only: the fee recipient's staking-mint account
+    #[account(mut)]
+    pub fee_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireLock<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct EnableReceiptMint<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority,
+        has_one = token_mint
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = authority,
+        mint::decimals = token_mint.decimals,
+        mint::authority = stake_pool,
+        seeds = [b"receipt_mint", stake_pool.key().as_ref()],
+        bump
+    )]
+    pub receipt_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct StakeLiquid<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    // Counts the wallet's deposits against its caps
+    #[account(
+        init_if_needed,
+        payer = user_authority,
+        space = 8 + WalletStake::INIT_SPACE,
+        seeds = [b"wallet_stake", user_authority.key().as_ref(), stake_pool.key().as_ref()],
+        bump
+    )]
+    pub wallet_stake: Account<'info, WalletStake>,
+    #[account(address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, address = stake_pool.receipt_mint)]
+    pub receipt_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, address = stake_pool.reward_streams[0].reward_vault)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_token_account.mint == stake_pool.token_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_receipt_account.mint == stake_pool.receipt_mint
+    )]
+    pub user_receipt_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
+    // Pools charging a reward fee only: the fee recipient's staking-mint account
+    #[account(mut)]
+    pub fee_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnstakeLiquid<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(address = stake_pool.token_mint)]
+    pub token_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, address = stake_pool.receipt_mint)]
+    pub receipt_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, address = stake_pool.reward_streams[0].reward_vault)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_token_account.mint == stake_pool.token_mint
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_receipt_account.mint == stake_pool.receipt_mint
+    )]
+    pub user_receipt_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, address = stake_pool.stake_vault)]
+    pub pool_token_account: InterfaceAccount<'info, TokenAccount>,
+    // Pools charging a reward fee only: the fee recipient's staking-mint account
+    #[account(mut)]
+    pub fee_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub user_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct EnablePositionNfts<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + PositionNftConfig::INIT_SPACE,
+        seeds = [b"position_nft_config", stake_pool.key().as_ref()],
+        bump
+    )]
+    pub position_config: Account<'info, PositionNftConfig>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct GetVotingPower<'info> {
+    #[account(
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct GetTotalVotingPower<'info> {
+    #[account(
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+}
+
+#[derive(Accounts)]
+pub struct CreateRegistrar<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Registrar::INIT_SPACE,
+        seeds = [b"registrar", realm.key().as_ref(), stake_pool.token_mint.as_ref()],
+        bump
+    )]
+    pub registrar: Account<'info, Registrar>,
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump,
+        has_one = authority,
+        constraint = stake_pool.realm == Pubkey::default() @ StakingError::RealmAlreadySet
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    /// CHECK: owner and layout are checked in the handler
+    pub realm: UncheckedAccount<'info>,
+    /// CHECK: only compared with the realm's owner
+    pub governance_program: UncheckedAccount<'info>,
+    pub realm_authority: Signer<'info>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+// Records are keyed by position, so an NFT position's record moves with the NFT
+#[derive(Accounts)]
+pub struct UpdateVoterWeightRecord<'info> {
+    #[account(
+        mut,
+        seeds = [b"stake_pool", stake_pool.token_mint.as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user_stake.owner.as_ref(), stake_pool.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.is_held_by(&user_authority.key(), &position_token_account)
+            @ StakingError::Unauthorized
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(
+        init_if_needed,
+        payer = user_authority,
+        space = 8 + VoterWeightRecord::INIT_SPACE,
+        seeds = [b"voter_weight_record", user_stake.key().as_ref()],
+        bump
+    )]
+    pub voter_weight_record: Account<'info, VoterWeightRecord>,
+    #[account(
+        init_if_needed,
+        payer = user_authority,
+        space = 8 + MaxVoterWeightRecord::INIT_SPACE,
+        seeds = [b"max_voter_weight_record", stake_pool.key().as_ref()],
+        bump
+    )]
+    pub max_voter_weight_record: Account<'info, MaxVoterWeightRecord>,
+    // NFT positions only: the caller's token account holding the position NFT
+    pub position_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct StakePool {
+    pub authority: Pubkey,         // Default once renounced
+    pub pending_authority: Pubkey, // Proposed successor; default when none
+    pub token_mint: Pubkey,
+    pub stake_vault: Pubkey,
+    pub total_staked: u64,
+    pub total_effective_stake: u64, // Sum of boosted balances; drives reward math
+    pub total_unbonding: u64,       // Requested unstakes still held in the stake vault
+    pub unbonding_period: i64,      // Seconds; zero allows instant unstake_tokens
+    pub early_exit_penalty: PenaltyConfig,
+    pub reward_fee_bps: u16,      // Protocol cut of every reward payout
+    pub fee_recipient: Pubkey,    // Wallet whose token accounts receive the cut
+    pub max_total_staked: u64,    // Zero for no cap
+    pub max_stake_per_user: u64,  // Per wallet; zero for no cap
+    pub min_stake_amount: u64,    // Smallest accepted deposit
+    pub allowlist_root: [u8; 32], // All zeros when anyone may stake
+    pub receipt_mint: Pubkey, // Default unless the pool is liquid
+    pub liquid_reward_index: u128, // Stream 0's accumulator at the last liquid harvest
+    pub liquid_deferred_rewards: u64, // Harvested while paused; not yet in the stake vault
+    pub position_nfts: bool,  // Positions are opened as NFTs via open_position
+    pub paused: bool,         // Blocks staking, compounding and claims
+    pub emergency_mode: bool, // Permanent; enables emergency_withdraw
+    pub voting_power: VotingPower,
+    pub realm: Pubkey, // SPL Governance realm; default until configured
+    pub realm_governing_token_mint: Pubkey,
+    pub realm_voting_period: i64, // Floor on the unbonding period while linked
+    pub lock_tier_count: u8,
+    pub lock_tiers: [LockTier; MAX_LOCK_TIERS],
+    pub reward_stream_count: u8,
+    pub reward_streams: [RewardStream; MAX_REWARD_STREAMS],
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RewardStream {
+    pub reward_mint: Pubkey,
+    pub reward_vault: Pubkey, // PDA token account owned by the pool
+    pub reward_rate: u64,     // Current campaign budget / duration, shared pro-rata
+    pub reward_budget: u64,
+    pub reward_start: i64,
+    pub reward_end: i64,
+    pub queued_campaign: RewardCampaign, // Zero budget when nothing is queued
+    pub reward_per_token_stored: u128,   // Scaled by REWARD_PRECISION
+    pub last_update_time: i64,
+    pub fees_collected: u64, // Protocol fees paid out of this stream, all time
+    pub unallocated: u64,    // In the vault but owed to nobody; see reclaim_rewards
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RewardCampaign {
+    pub budget: u64,
+    pub start: i64,
+    pub end: i64,
+}
+
+// Leaves are keccak(wallet || cap as u64 LE) and each level hashes the sorted
+// pair of children. A zero cap leaves the wallet bound only by the pool limits.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AllowlistProof {
+    pub cap: u64,
+    pub proof: Vec<[u8; 32]>,
+}
+
+// Fields left as `None` are not changed
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PoolConfigUpdate {
+    pub lock_tiers: Option<Vec<LockTier>>,
+    pub unbonding_period: Option<i64>,
+    pub early_exit_penalty: Option<PenaltyConfig>,
+    pub max_total_staked: Option<u64>,
+    pub max_stake_per_user: Option<u64>,
+    pub min_stake_amount: Option<u64>,
+    pub reward_fee_bps: Option<u16>,
+    pub fee_recipient: Option<Pubkey>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct LockTier {
+    pub duration: i64, // Seconds; zero for an unlocked tier
+    pub multiplier_bps: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct VotingPower {
+    pub bias: u128, // Sum of amount * (voting_lock_end - last_checkpoint) over live locks
+    pub slope: u64, // Sum of amounts whose locks have not ended yet
+    pub last_checkpoint: i64,
+    pub slope_changes: [u64; VE_SLOPE_SLOTS], // Amount unlocking at each week, by week % slots
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct PenaltyConfig {
+    pub curve: PenaltyCurve,
+    pub penalty_bps: u16,
+    pub destination: PenaltyDestination,
+    pub treasury: Pubkey, // Stake-mint token account; only used by `Treasury`
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PenaltyCurve {
+    None,        // Locked stake cannot leave early
+    Flat,        // `penalty_bps` of the amount, regardless of time left
+    LinearDecay, // `penalty_bps` at lock start, falling to zero at `lock_end`
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PenaltyDestination {
+    Treasury,
+    Burn,
+    Redistribute, // Added to stream 0, which must pay out the staking mint
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct PositionNftConfig {
+    pub stake_pool: Pubkey,
+    #[max_len(32)]
+    pub name: String,
+    #[max_len(10)]
+    pub symbol: String,
+    #[max_len(96)]
+    pub uri: String,
+    pub bump: u8,
+}
+
+// Stake a wallet has put into NFT positions or liquid receipts, for its caps.
+// Both can change hands, so this counts all the wallet has deposited, not what
+// it holds.
+#[account]
+#[derive(InitSpace)]
+pub struct WalletStake {
+    pub opened: u64,
+    pub bump: u8,
+}
+
+// Links a pool to the realm and governing mint in its seeds; created with the
+// realm authority's consent
+#[account]
+#[derive(InitSpace)]
+pub struct Registrar {
+    pub stake_pool: Pubkey,
+    pub governance_program: Pubkey,
+    pub realm: Pubkey,
+    pub governing_token_mint: Pubkey,
+    pub bump: u8,
+}
+
+// Leading fields of an SPL Governance realm, which RealmV1 and RealmV2 share:
+// enough to tell who controls it and which mints it governs
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct RealmHeader {
+    pub account_type: u8,
+    pub community_mint: Pubkey,
+    pub config: RealmConfigHeader,
+    pub reserved: [u8; 6],
+    pub legacy: u16,
+    pub authority: Option<Pubkey>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct RealmConfigHeader {
+    pub legacy: [u8; 2],
+    pub reserved: [u8; 6],
+    pub min_community_weight_to_create_governance: u64,
+    pub community_mint_max_voter_weight_source: (u8, u64), // Enum tag and value
+    pub council_mint: Option<Pubkey>,
+}
+
+// Layout of the SPL Governance addin records. Anchor's discriminators for
+// these names match the ones spl-governance-addin-api expects.
+#[account]
+#[derive(InitSpace)]
+pub struct VoterWeightRecord {
+    pub realm: Pubkey,
+    pub governing_token_mint: Pubkey,
+    pub governing_token_owner: Pubkey,
+    pub voter_weight: u64,
+    pub voter_weight_expiry: Option<u64>, // Slot after which governance rejects the record
+    pub weight_action: Option<VoterWeightAction>,
+    pub weight_action_target: Option<Pubkey>,
+    pub reserved: [u8; 8],
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct MaxVoterWeightRecord {
+    pub realm: Pubkey,
+    pub governing_token_mint: Pubkey,
+    pub max_voter_weight: u64,
+    pub max_voter_weight_expiry: Option<u64>,
+    pub reserved: [u8; 8],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum VoterWeightAction {
+    CastVote,
+    CommentProposal,
+    CreateGovernance,
+    CreateProposal,
+    SignOffProposal,
+}
+
+#[account]
+pub struct UserStake {
+    pub owner: Pubkey,       // Owning wallet, or the position NFT mint for NFT positions
+    pub nft_position: bool,
+    pub amount: u64,
+    pub effective_amount: u64, // `amount` scaled by `multiplier_bps`
+    pub staked_at: i64,
+    pub lock_start: i64,
+    pub lock_end: i64,
+    pub multiplier_bps: u16,
+    pub unbonding_amount: u64, // Requested for withdrawal; earns nothing
+    pub cooldown_ends_at: i64,
+    pub auto_compound: bool, // Lets anyone crank `compound_for` on this position
+    pub voting_amount: u64,   // Amount and week-rounded lock end registered
+    pub voting_lock_end: i64, // in the pool's voting power
+    pub rewards: [UserReward; MAX_REWARD_STREAMS], // Indexed like `StakePool::reward_streams`
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct UserReward {
+    pub reward_per_token_paid: u128, // Accumulator value at last settlement
+    pub pending_rewards: u64,        // Settled but not yet paid out
+}
+
+#[event]
+pub struct PoolInitialized {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub authority: Pubkey,
+    pub token_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub reward_vault: Pubkey,
+    pub unbonding_period: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ConfigUpdated {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub authority: Pubkey,
+    pub update: PoolConfigUpdate,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PauseUpdated {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub paused: bool,
+    pub emergency_mode: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AllowlistRootUpdated {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub allowlist_root: [u8; 32],
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardStreamAdded {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub stream_index: u8,
+    pub reward_mint: Pubkey,
+    pub reward_vault: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardRateUpdated {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub stream_index: u8,
+    pub reward_rate: u64,
+    pub reward_end: i64, // Moved so the campaign's remaining budget is spent at the new rate
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardsReclaimed {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub stream_index: u8,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardCampaignQueued {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub stream_index: u8,
+    pub budget: u64, // Net of transfer fees
+    pub reward_rate: u64,
+    pub start: i64,
+    pub end: i64,
+    pub started: bool, // False when queued behind the running campaign
+    pub timestamp: i64,
+}
+
+// Balances in position events are the values after the instruction. Reward
+// indexes are the per-stream accumulators, in stream order.
+#[event]
+pub struct Staked {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64, // Net of transfer fees
+    pub user_amount: u64,
+    pub total_staked: u64,
+    pub lock_end: i64,
+    pub reward_indexes: [u128; MAX_REWARD_STREAMS],
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Unstaked {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub penalty: u64,
+    pub user_amount: u64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct UnstakeRequested {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub user_amount: u64,
+    pub unbonding_amount: u64,
+    pub cooldown_ends_at: i64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct UnstakeWithdrawn {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub total_unbonding: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct UnstakeCancelled {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub user_amount: u64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct EmergencyWithdrawn {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardsClaimed {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub amounts: [u64; MAX_REWARD_STREAMS], // Paid to the owner per stream, before transfer fees
+    pub fees: [u64; MAX_REWARD_STREAMS],    // Protocol fee taken per stream
+    pub reward_indexes: [u128; MAX_REWARD_STREAMS],
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Compounded {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub reward: u64, // Moved to the stake vault, after the protocol fee
+    pub fee: u64,
+    pub restaked: u64, // `reward` less transfer fees
+    pub user_amount: u64,
+    pub total_staked: u64,
+    pub reward_index: u128, // Stream 0's accumulator
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AutoCompoundUpdated {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub enabled: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct LockExpired {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub effective_amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReceiptMintEnabled {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub receipt_mint: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct LiquidStaked {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64, // As received, after any transfer fee
+    pub receipts: u64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct LiquidUnstaked {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub owner: Pubkey,
+    pub receipts: u64,
+    pub amount: u64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct LiquidRewardsHarvested {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub amount: u64, // Restaked, after the reward fee and any transfer fee
+    pub fee: u64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PositionNftsEnabled {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub position_config: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RegistrarCreated {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub registrar: Pubkey,
+    pub realm: Pubkey,
+    pub governing_token_mint: Pubkey,
+    pub voting_period: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct VoterWeightUpdated {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub user_stake: Pubkey,
+    pub owner: Pubkey,
+    pub voter_weight: u64,
+    pub max_voter_weight: u64,
+    pub expiry_slot: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AuthorityTransferProposed {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub authority: Pubkey,
+    pub pending_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AuthorityTransferCancelled {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub authority: Pubkey,
+    pub pending_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AuthorityTransferred {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub previous_authority: Pubkey,
+    pub new_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AuthorityRenounced {
+    pub version: u8,
+    pub stake_pool: Pubkey,
+    pub previous_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+impl StakePool {
+    // Seeds for signing as the pool PDA, which owns every vault
+    pub fn signer_seeds(&self) -> [&[u8]; 3] {
+        [
+            b"stake_pool",
+            self.token_mint.as_ref(),
+            std::slice::from_ref(&self.bump),
+        ]
+    }
+
+    pub fn is_liquid(&self) -> bool {
+        self.receipt_mint != Pubkey::default()
+    }
+
+    // Receipts and position NFTs let a position change owner
+    pub fn has_transferable_positions(&self) -> bool {
+        self.is_liquid() || self.position_nfts
+    }
+
+    // Folds stream 0's emissions since the last harvest into a liquid pool's
+    // stake, as if all of it were one position. Returns the rewards to move
+    // from the reward vault into the stake vault and the fee taken from them.
+    // While the pool is paused nothing moves: the rewards wait in
+    // `liquid_deferred_rewards` until a harvest after it is unpaused.
+    pub fn harvest_liquid(&mut self, now: i64) -> Result<(u64, u64)> {
+        self.update_rewards(now)?;
+        let index = self.reward_streams[0].reward_per_token_stored;
+        let delta = index
+            .checked_sub(self.liquid_reward_index)
+            .ok_or(StakingError::MathOverflow)?;
+        let reward = math::earned(self.total_effective_stake, delta)?;
+        self.liquid_reward_index = index;
+        self.liquid_deferred_rewards = self
+            .liquid_deferred_rewards
+            .checked_add(reward)
+            .ok_or(StakingError::MathOverflow)?;
+        if self.paused {
+            return Ok((0, 0));
+        }
+
+        let reward = std::mem::take(&mut self.liquid_deferred_rewards);
+        let fee = self.reward_fee(reward)?;
+        let mut fees = [0; MAX_REWARD_STREAMS];
+        fees[0] = fee;
+        self.record_fees(&fees)?;
+        Ok((reward - fee, fee))
+    }
+
+    // Credits harvested rewards, as they arrived in the stake vault, to every
+    // receipt holder by raising what each receipt redeems for
+    pub fn add_liquid_rewards(&mut self, received: u64) -> Result<()> {
+        self.total_staked = self
+            .total_staked
+            .checked_add(received)
+            .ok_or(StakingError::MathOverflow)?;
+        self.total_effective_stake = self
+            .total_effective_stake
+            .checked_add(received)
+            .ok_or(StakingError::MathOverflow)?;
+        Ok(())
+    }
+
+    // Adds `amount` to a liquid pool's stake and returns the receipts it buys:
+    // the same share of the receipt supply as the amount is of the stake after
+    // harvesting, rounded down. The first deposit sets the rate at 1:1.
+    pub fn deposit_liquid(&mut self, amount: u64, receipt_supply: u64) -> Result<u64> {
+        let receipts = if receipt_supply == 0 {
+            amount
+        } else {
+            math::to_u64(math::mul_div(
+                amount as u128,
+                receipt_supply as u128,
+                self.total_staked as u128,
+                Rounding::Down,
+            )?)?
+        };
+        require!(receipts > 0, StakingError::InvalidAmount);
+        self.add_liquid_rewards(amount)?;
+        Ok(receipts)
+    }
+
+    // Removes the stake behind `receipts` from a liquid pool and returns it,
+    // rounded down so the holders that remain never cover the difference
+    pub fn redeem_liquid(&mut self, receipts: u64, receipt_supply: u64) -> Result<u64> {
+        require!(
+            receipts > 0 && receipts <= receipt_supply,
+            StakingError::InvalidAmount
+        );
+        let amount = math::to_u64(math::mul_div(
+            receipts as u128,
+            self.total_staked as u128,
+            receipt_supply as u128,
+            Rounding::Down,
+        )?)?;
+        self.total_staked -= amount;
+        self.total_effective_stake -= amount;
+        // Rewards held back by a pause have no one left to go to
+        if receipts == receipt_supply {
+            let deferred = std::mem::take(&mut self.liquid_deferred_rewards);
+            self.reward_streams[0].add_unallocated(deferred)?;
+        }
+        Ok(amount)
+    }
+
+    // Brings the pool and a position up to `now`, leaving accrued rewards in
+    // `pending_rewards`
+    pub fn checkpoint(&mut self, user_stake: &mut UserStake, now: i64) -> Result<()> {
+        self.update_rewards(now)?;
+        user_stake.settle(self)
+    }
+
+    // Every stream's accumulator, zero for unused slots
+    pub fn reward_indexes(&self) -> [u128; MAX_REWARD_STREAMS] {
+        let mut indexes = [0; MAX_REWARD_STREAMS];
+        for (index, stream) in self.active_streams().iter().enumerate() {
+            indexes[index] = stream.reward_per_token_stored;
+        }
+        indexes
+    }
+
+    pub fn active_streams(&self) -> &[RewardStream] {
+        &self.reward_streams[..self.reward_stream_count as usize]
+    }
+
+    pub fn stream(&self, index: u8) -> Result<&RewardStream> {
+        self.active_streams()
+            .get(index as usize)
+            .ok_or(error!(StakingError::InvalidRewardStream))
+    }
+
+    pub fn lock_tier(&self, index: u8) -> Result<LockTier> {
+        self.lock_tiers[..self.lock_tier_count as usize]
+            .get(index as usize)
+            .copied()
+            .ok_or(error!(StakingError::InvalidLockTier))
+    }
+
+    pub fn set_lock_tiers(&mut self, tiers: &[LockTier]) -> Result<()> {
+        require!(
+            !tiers.is_empty() && tiers.len() <= MAX_LOCK_TIERS,
+            StakingError::InvalidLockTierConfig
+        );
+        for tier in tiers {
+            require!(
+                tier.duration >= 0 && tier.duration <= MAX_LOCK_DURATION,
+                StakingError::InvalidLockTierConfig
+            );
+            require!(
+                !self.is_liquid() || tier.duration == 0,
+                StakingError::LiquidPoolLocks
+            );
+            require!(
+                tier.multiplier_bps as u64 >= BPS_DENOMINATOR,
+                StakingError::InvalidLockTierConfig
+            );
+            // Only a lock can earn a boost
+            require!(
+                tier.duration > 0 || tier.multiplier_bps as u64 == BPS_DENOMINATOR,
+                StakingError::InvalidLockTierConfig
+            );
+        }
+        self.lock_tiers = [LockTier::default(); MAX_LOCK_TIERS];
+        self.lock_tiers[..tiers.len()].copy_from_slice(tiers);
+        self.lock_tier_count = tiers.len() as u8;
+        Ok(())
+    }
+
+    pub fn set_unbonding_period(&mut self, unbonding_period: i64) -> Result<()> {
+        require!(
+            (0..=MAX_UNBONDING_PERIOD).contains(&unbonding_period),
+            StakingError::InvalidUnbondingPeriod
+        );
+        // Receipts are redeemed on the spot, with no queue to wait in
+        require!(
+            unbonding_period == 0 || !self.is_liquid(),
+            StakingError::LiquidPoolLocks
+        );
+        require!(
+            unbonding_period >= self.realm_voting_period,
+            StakingError::UnbondingShorterThanVoting
+        );
+        self.unbonding_period = unbonding_period;
+        Ok(())
+    }
+
+    pub fn set_stake_limits(
+        &mut self,
+        max_total_staked: u64,
+        max_stake_per_user: u64,
+        min_stake_amount: u64,
+    ) -> Result<()> {
+        require!(
+            max_stake_per_user == 0 || min_stake_amount <= max_stake_per_user,
+            StakingError::InvalidStakeLimits
+        );
+        require!(
+            max_total_staked == 0 || min_stake_amount <= max_total_staked,
+            StakingError::InvalidStakeLimits
+        );
+        self.max_total_staked = max_total_staked;
+        self.max_stake_per_user = max_stake_per_user;
+        self.min_stake_amount = min_stake_amount;
+        Ok(())
+    }
+
+    pub fn set_reward_fee(&mut self, reward_fee_bps: u16, fee_recipient: Pubkey) -> Result<()> {
+        require!(
+            reward_fee_bps <= MAX_REWARD_FEE_BPS,
+            StakingError::InvalidRewardFee
+        );
+        require!(
+            reward_fee_bps == 0 || fee_recipient != Pubkey::default(),
+            StakingError::InvalidRewardFee
+        );
+        self.reward_fee_bps = reward_fee_bps;
+        self.fee_recipient = fee_recipient;
+        Ok(())
+    }
+
+    // The protocol's cut of a `reward` payout, rounded down so that payouts too
+    // small to carry a whole token of fee go to the staker in full
+    pub fn reward_fee(&self, reward: u64) -> Result<u64> {
+        math::apply_bps(reward, self.reward_fee_bps as u64, Rounding::Down)
+    }
+
+    pub fn record_fees(&mut self, fees: &[u64; MAX_REWARD_STREAMS]) -> Result<()> {
+        let count = self.reward_stream_count as usize;
+        for (stream, fee) in self.reward_streams[..count].iter_mut().zip(fees) {
+            stream.fees_collected = stream
+                .fees_collected
+                .checked_add(*fee)
+                .ok_or(StakingError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    // Checks a deposit of `amount` into a position currently holding `position_amount`
+    pub fn check_stake_limits(&self, position_amount: u64, amount: u64) -> Result<()> {
+        require!(amount >= self.min_stake_amount, StakingError::StakeBelowMinimum);
+        self.check_stake_caps(position_amount, amount)
+    }
+
+    // The per-user and pool caps alone; they also bound compounded rewards and
+    // cancelled unstakes, which have no minimum
+    pub fn check_stake_caps(&self, position_amount: u64, amount: u64) -> Result<()> {
+        self.check_user_cap(position_amount, amount)?;
+        let total_staked = self
+            .total_staked
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(
+            self.max_total_staked == 0 || total_staked <= self.max_total_staked,
+            StakingError::PoolStakeCapExceeded
+        );
+        Ok(())
+    }
+
+    fn check_user_cap(&self, position_amount: u64, amount: u64) -> Result<()> {
+        let position_amount = position_amount
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(
+            self.max_stake_per_user == 0 || position_amount <= self.max_stake_per_user,
+            StakingError::UserStakeCapExceeded
+        );
+        Ok(())
+    }
+
+    pub fn has_allowlist(&self) -> bool {
+        self.allowlist_root != [0; 32]
+    }
+
+    // Checks `wallet` against the allowlist, if the pool has one, and holds the
+    // deposit to the wallet's cap
+    pub fn check_allowlist(
+        &self,
+        wallet: &Pubkey,
+        proof: Option<&AllowlistProof>,
+        position_amount: u64,
+        amount: u64,
+    ) -> Result<()> {
+        if !self.has_allowlist() {
+            return Ok(());
+        }
+        let proof = proof.ok_or(StakingError::NotAllowlisted)?;
+
+        let mut node = keccak::hashv(&[wallet.as_ref(), &proof.cap.to_le_bytes()]).0;
+        for sibling in &proof.proof {
+            node = if node <= *sibling {
+                keccak::hashv(&[&node, sibling]).0
+            } else {
+                keccak::hashv(&[sibling, &node]).0
+            };
+        }
+        require!(node == self.allowlist_root, StakingError::NotAllowlisted);
+
+        let position_amount = position_amount
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(
+            proof.cap == 0 || position_amount <= proof.cap,
+            StakingError::AllowlistCapExceeded
+        );
+        Ok(())
+    }
+
+    pub fn set_early_exit_penalty(&mut self, config: PenaltyConfig) -> Result<()> {
+        require!(
+            config.penalty_bps as u64 <= BPS_DENOMINATOR,
+            StakingError::InvalidPenaltyConfig
+        );
+        match config.destination {
+            PenaltyDestination::Treasury => require!(
+                config.treasury != Pubkey::default(),
+                StakingError::InvalidPenaltyConfig
+            ),
+            PenaltyDestination::Redistribute => require_keys_eq!(
+                self.reward_streams[0].reward_mint,
+                self.token_mint,
+                StakingError::InvalidPenaltyConfig
+            ),
+            PenaltyDestination::Burn => {}
+        }
+        self.early_exit_penalty = config;
+        Ok(())
+    }
+
+    // Recomputes a position's boosted balance and voting power and folds the
+    // changes into the pool totals. Expired locks fall back to no boost.
+    // Rewards must be settled first.
+    pub fn sync_effective_stake(&mut self, user_stake: &mut UserStake, now: i64) -> Result<()> {
+        if now >= user_stake.lock_end {
+            user_stake.multiplier_bps = BPS_DENOMINATOR as u16;
+        }
+        let effective = math::apply_bps(
+            user_stake.amount,
+            user_stake.multiplier_bps as u64,
+            Rounding::Down,
+        )?;
+        self.total_effective_stake = self
+            .total_effective_stake
+            .checked_sub(user_stake.effective_amount)
+            .and_then(|total| total.checked_add(effective))
+            .ok_or(StakingError::MathOverflow)?;
+        user_stake.effective_amount = effective;
+
+        let voting_lock_end = user_stake.lock_end / WEEK * WEEK;
+        self.voting_power.checkpoint(now)?;
+        self.voting_power.replace_lock(
+            (user_stake.voting_amount, user_stake.voting_lock_end),
+            (user_stake.amount, voting_lock_end),
+            now,
+        )?;
+        user_stake.voting_amount = user_stake.amount;
+        user_stake.voting_lock_end = voting_lock_end;
+        Ok(())
+    }
+
+    // Adds `amount` to a settled position under `tier` and updates the totals
+    pub fn deposit(
+        &mut self,
+        user_stake: &mut UserStake,
+        tier: LockTier,
+        amount: u64,
+        now: i64,
+    ) -> Result<()> {
+        self.total_staked = self
+            .total_staked
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        user_stake.amount = user_stake
+            .amount
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        user_stake.staked_at = now;
+        user_stake.apply_lock_tier(tier, now)?;
+        self.sync_effective_stake(user_stake, now)
+    }
+
+    // Takes `amount` out of a settled position and returns the early-exit
+    // penalty owed on it, which the caller routes per the penalty config
+    pub fn withdraw(&mut self, user_stake: &mut UserStake, amount: u64, now: i64) -> Result<u64> {
+        require!(amount > 0, StakingError::InvalidAmount);
+        require!(user_stake.amount >= amount, StakingError::InsufficientStake);
+
+        // Leaving before lock_end costs a penalty, if the pool allows it at all
+        let penalty = if now < user_stake.lock_end {
+            require!(
+                self.early_exit_penalty.curve != PenaltyCurve::None,
+                StakingError::StakeLocked
+            );
+            self.early_exit_penalty.penalty_for(amount, user_stake, now)?
+        } else {
+            0
+        };
+
+        self.total_staked = self
+            .total_staked
+            .checked_sub(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        user_stake.amount -= amount;
+        self.sync_effective_stake(user_stake, now)?;
+        Ok(penalty)
+    }
+
+    // Restakes stream 0's pending rewards in place, less the protocol fee.
+    // Returns the amount the caller must move from the reward vault to the stake
+    // vault, the fee it must send to the fee recipient, and the part of the
+    // first that arrives after transfer fees, which is what gets staked.
+    pub fn compound(
+        &mut self,
+        user_stake: &mut UserStake,
+        allowlist_proof: Option<&AllowlistProof>,
+        token_mint: &InterfaceAccount<Mint>,
+        now: i64,
+    ) -> Result<(u64, u64, u64)> {
+        require_keys_eq!(
+            self.reward_streams[0].reward_mint,
+            self.token_mint,
+            StakingError::CompoundUnsupported
+        );
+        // Liquid pools compound for every holder through the redemption rate
+        require!(!self.is_liquid(), StakingError::LiquidStakesOnly);
+
+        self.checkpoint(user_stake, now)?;
+
+        let reward = user_stake.rewards[0].pending_rewards;
+        require!(reward > 0, StakingError::NothingToCompound);
+        user_stake.rewards[0].pending_rewards = 0;
+        let fee = self.reward_fee(reward)?;
+        let reward = reward - fee;
+        let restaked = amount_after_fee(token_mint, reward)?;
+        self.check_stake_caps(user_stake.amount, restaked)?;
+        // NFT positions belong to no wallet; their openers were capped already
+        if !user_stake.nft_position {
+            let principal = user_stake.principal()?;
+            self.check_allowlist(&user_stake.owner, allowlist_proof, principal, restaked)?;
+        }
+        let mut fees = [0; MAX_REWARD_STREAMS];
+        fees[0] = fee;
+        self.record_fees(&fees)?;
+
+        self.total_staked = self
+            .total_staked
+            .checked_add(restaked)
+            .ok_or(StakingError::MathOverflow)?;
+        user_stake.amount = user_stake
+            .amount
+            .checked_add(restaked)
+            .ok_or(StakingError::MathOverflow)?;
+        self.sync_effective_stake(user_stake, now)?;
+
+        Ok((reward, fee, restaked))
+    }
+
+    pub fn stream_mut(&mut self, index: u8) -> Result<&mut RewardStream> {
+        let count = self.reward_stream_count as usize;
+        self.reward_streams[..count]
+            .get_mut(index as usize)
+            .ok_or(error!(StakingError::InvalidRewardStream))
+    }
+
+    // Must run before anything that changes `total_effective_stake` or a stream's rate
+    pub fn update_rewards(&mut self, now: i64) -> Result<()> {
+        let total_effective_stake = self.total_effective_stake;
+        let count = self.reward_stream_count as usize;
+        for stream in self.reward_streams[..count].iter_mut() {
+            stream.update(total_effective_stake, now)?;
+        }
+        Ok(())
+    }
+}
+
+impl RewardStream {
+    // An idle stream: zero-length window and nothing queued
+    pub fn new(reward_mint: Pubkey, reward_vault: Pubkey, now: i64) -> Self {
+        Self {
+            reward_mint,
+            reward_vault,
+            reward_start: now,
+            reward_end: now,
+            last_update_time: now,
+            ..Default::default()
+        }
+    }
+
+    pub fn start_campaign(&mut self, campaign: RewardCampaign) -> Result<()> {
+        self.reward_rate = campaign.rate()?;
+        self.reward_budget = campaign.budget;
+        self.reward_start = campaign.start;
+        self.reward_end = campaign.end;
+        // The rate rounds down, so part of the budget is never emitted
+        let emitted = (campaign.end - campaign.start) as u64 * self.reward_rate;
+        self.add_unallocated(campaign.budget - emitted)
+    }
+
+    // Re-paces the current campaign from `now` (or its start, if later): what
+    // it has left to emit is spent at `rate`, moving its end. Accrual must be
+    // settled up to `now`. A queued campaign must still start after the new end.
+    pub fn set_rate(&mut self, rate: u64, now: i64) -> Result<()> {
+        require!(now < self.reward_end, StakingError::NoActiveCampaign);
+        require!(rate > 0, StakingError::InvalidRewardRate);
+
+        let from = now.max(self.reward_start);
+        let remaining = ((self.reward_end - from) as u128)
+            .checked_mul(self.reward_rate as u128)
+            .ok_or(StakingError::MathOverflow)?;
+        let duration = remaining / rate as u128;
+        require!(duration > 0, StakingError::InvalidRewardRate);
+        let budget = math::to_u64(duration * rate as u128)?;
+        let end = i64::try_from(duration)
+            .ok()
+            .and_then(|duration| from.checked_add(duration))
+            .ok_or(StakingError::MathOverflow)?;
+        require!(
+            self.queued_campaign.budget == 0 || self.queued_campaign.start >= end,
+            StakingError::CampaignOverlap
+        );
+
+        self.reward_rate = rate;
+        self.reward_budget = budget;
+        self.reward_start = from;
+        self.reward_end = end;
+        self.add_unallocated(math::to_u64(remaining)? - budget)
+    }
+
+    // Starts `campaign` straight away if nothing is running or queued, and
+    // otherwise queues it behind the current one. Returns whether it started.
+    pub fn queue_campaign(&mut self, campaign: RewardCampaign, now: i64) -> Result<bool> {
+        require!(campaign.start < campaign.end, StakingError::InvalidRewardWindow);
+        require!(campaign.start >= now, StakingError::InvalidRewardWindow);
+        require!(campaign.rate()? > 0, StakingError::BudgetTooSmall);
+
+        if now >= self.reward_end && self.queued_campaign.budget == 0 {
+            self.start_campaign(campaign)?;
+            return Ok(true);
+        }
+        require!(
+            self.queued_campaign.budget == 0,
+            StakingError::CampaignAlreadyQueued
+        );
+        require!(
+            campaign.start >= self.reward_end,
+            StakingError::CampaignOverlap
+        );
+        self.queued_campaign = campaign;
+        Ok(false)
+    }
+
+    // Accumulator value as of `now`, with accrual clamped to the campaign window.
+    // Does not look at the queued campaign; `update` handles the rollover.
+    pub fn reward_per_token(&self, total_effective_stake: u64, now: i64) -> Result<u128> {
+        let emitted = self.emitted_since_update(now)?;
+        if total_effective_stake == 0 || emitted == 0 {
+            return Ok(self.reward_per_token_stored);
+        }
+        let accrued = math::reward_per_token(emitted, total_effective_stake)?;
+        Ok(self
+            .reward_per_token_stored
+            .checked_add(accrued)
+            .ok_or(StakingError::MathOverflow)?)
+    }
+
+    pub fn update(&mut self, total_effective_stake: u64, now: i64) -> Result<()> {
+        // Close out the current campaign and switch to the queued one
+        if self.queued_campaign.budget > 0 && now >= self.reward_end {
+            self.accrue(total_effective_stake, self.reward_end)?;
+            self.start_campaign(self.queued_campaign)?;
+            self.queued_campaign = RewardCampaign::default();
+        }
+        self.accrue(total_effective_stake, now)
+    }
+
+    // Credits a lump sum to current stakers straight into the accumulator.
+    // Fails when nobody is staked; callers send the tokens elsewhere instead.
+    pub fn distribute(&mut self, amount: u64, total_effective_stake: u64) -> Result<()> {
+        let accrued = math::reward_per_token(amount as u128, total_effective_stake)?;
+        self.reward_per_token_stored = self
+            .reward_per_token_stored
+            .checked_add(accrued)
+            .ok_or(StakingError::MathOverflow)?;
+        Ok(())
+    }
+
+    // What the current campaign emits between the last update and `now`
+    fn emitted_since_update(&self, now: i64) -> Result<u128> {
+        let from = self.last_update_time.max(self.reward_start);
+        let to = now.min(self.reward_end);
+        if to <= from {
+            return Ok(0);
+        }
+        Ok(((to - from) as u128)
+            .checked_mul(self.reward_rate as u128)
+            .ok_or(StakingError::MathOverflow)?)
+    }
+
+    fn add_unallocated(&mut self, amount: u64) -> Result<()> {
+        self.unallocated = self
+            .unallocated
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        Ok(())
+    }
+
+    fn accrue(&mut self, total_effective_stake: u64, now: i64) -> Result<()> {
+        // With nobody staked the emissions have no one to go to
+        if total_effective_stake == 0 {
+            let emitted = self.emitted_since_update(now)?;
+            self.add_unallocated(math::to_u64(emitted)?)?;
+        }
+        self.reward_per_token_stored = self.reward_per_token(total_effective_stake, now)?;
+        self.last_update_time = self.last_update_time.max(now);
+        Ok(())
+    }
+}
+
+impl PenaltyConfig {
+    // Penalty for withdrawing `amount` from a position that is still locked,
+    // rounded up
+    pub fn penalty_for(&self, amount: u64, user_stake: &UserStake, now: i64) -> Result<u64> {
+        match self.curve {
+            PenaltyCurve::None => Ok(0),
+            PenaltyCurve::Flat => math::apply_bps(amount, self.penalty_bps as u64, Rounding::Up),
+            PenaltyCurve::LinearDecay => {
+                let remaining = (user_stake.lock_end - now).max(0) as u128;
+                let duration = (user_stake.lock_end - user_stake.lock_start).max(1) as u128;
+                let penalty = math::mul_div(
+                    amount as u128 * self.penalty_bps as u128,
+                    remaining,
+                    duration * BPS_DENOMINATOR as u128,
+                    Rounding::Up,
+                )?;
+                math::to_u64(penalty)
+            }
+        }
+    }
+}
+
+impl VotingPower {
+    fn slot(week: i64) -> usize {
+        (week / WEEK) as usize % VE_SLOPE_SLOTS
+    }
+
+    // Decays the total up to `now`, dropping locks as their week ends
+    pub fn checkpoint(&mut self, now: i64) -> Result<()> {
+        while self.last_checkpoint < now {
+            // Nothing left to decay, so no slope changes are pending either
+            if self.slope == 0 {
+                self.last_checkpoint = now;
+                break;
+            }
+            let next_week = (self.last_checkpoint / WEEK + 1) * WEEK;
+            let step_to = next_week.min(now);
+            let decay = self.slope as u128 * (step_to - self.last_checkpoint) as u128;
+            self.bias = self.bias.checked_sub(decay).ok_or(StakingError::MathOverflow)?;
+            self.last_checkpoint = step_to;
+            if step_to == next_week {
+                let slot = Self::slot(next_week);
+                self.slope = self
+                    .slope
+                    .checked_sub(self.slope_changes[slot])
+                    .ok_or(StakingError::MathOverflow)?;
+                self.slope_changes[slot] = 0;
+            }
+        }
+        Ok(())
+    }
+
+    // Swaps a position's (amount, week-rounded lock end) for a new one. The
+    // total must already be checkpointed to `now`.
+    pub fn replace_lock(&mut self, old: (u64, i64), new: (u64, i64), now: i64) -> Result<()> {
+        let (old_amount, old_end) = old;
+        if old_end > now && old_amount > 0 {
+            let bias = old_amount as u128 * (old_end - now) as u128;
+            self.bias = self.bias.checked_sub(bias).ok_or(StakingError::MathOverflow)?;
+            self.slope = self.slope.checked_sub(old_amount).ok_or(StakingError::MathOverflow)?;
+            let slot = Self::slot(old_end);
+            self.slope_changes[slot] = self.slope_changes[slot]
+                .checked_sub(old_amount)
+                .ok_or(StakingError::MathOverflow)?;
+        }
+
+        let (new_amount, new_end) = new;
+        if new_end > now && new_amount > 0 {
+            let bias = new_amount as u128 * (new_end - now) as u128;
+            self.bias = self.bias.checked_add(bias).ok_or(StakingError::MathOverflow)?;
+            self.slope = self.slope.checked_add(new_amount).ok_or(StakingError::MathOverflow)?;
+            let slot = Self::slot(new_end);
+            self.slope_changes[slot] = self.slope_changes[slot]
+                .checked_add(new_amount)
+                .ok_or(StakingError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    pub fn total_at(&self, timestamp: i64) -> Result<u64> {
+        require!(
+            timestamp >= self.last_checkpoint,
+            StakingError::TimestampBeforeCheckpoint
+        );
+        let mut projected = *self;
+        projected.checkpoint(timestamp)?;
+        math::to_u64(projected.bias / MAX_LOCK_DURATION as u128)
+    }
+}
+
+impl RewardCampaign {
+    // Emission rate that spends the budget evenly across the window
+    pub fn rate(&self) -> Result<u64> {
+        let duration = self
+            .end
+            .checked_sub(self.start)
+            .filter(|d| *d > 0)
+            .ok_or(StakingError::InvalidRewardWindow)?;
+        Ok(self.budget / duration as u64)
+    }
+}
+
+impl UserStake {
+    // Whether `holder` controls the position: the owning wallet, or for NFT
+    // positions whoever holds the NFT
+    pub fn is_held_by(&self, holder: &Pubkey, position_token_account: &Option<InterfaceAccount<TokenAccount>>) -> bool {
+        if !self.nft_position {
+            return self.owner == *holder;
+        }
+        position_token_account.as_ref().is_some_and(|account| {
+            account.mint == self.owner && account.owner == *holder && account.amount == 1
+        })
+    }
+
+    // Staked plus unbonding: everything a cancelled unstake could put back
+    pub fn principal(&self) -> Result<u64> {
+        let principal = self
+            .amount
+            .checked_add(self.unbonding_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        Ok(principal)
+    }
+
+    pub fn voting_power_at(&self, timestamp: i64) -> Result<u64> {
+        if timestamp >= self.voting_lock_end {
+            return Ok(0);
+        }
+        let remaining = self
+            .voting_lock_end
+            .checked_sub(timestamp)
+            .ok_or(StakingError::MathOverflow)?;
+        let power = math::mul_div(
+            self.voting_amount as u128,
+            remaining as u128,
+            MAX_LOCK_DURATION as u128,
+            Rounding::Down,
+        )?;
+        math::to_u64(power)
+    }
+
+    // Re-locks the whole position under `tier` from `now`. A lock can be
+    // extended but never shortened, and a tier that ends sooner is refused:
+    // its deposit would otherwise share the longer lock's multiplier.
+    pub fn apply_lock_tier(&mut self, tier: LockTier, now: i64) -> Result<()> {
+        let lock_end = now
+            .checked_add(tier.duration)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(lock_end >= self.lock_end, StakingError::LockTierTooShort);
+        self.lock_start = now;
+        self.lock_end = lock_end;
+        self.multiplier_bps = tier.multiplier_bps;
+        Ok(())
+    }
+
+    // Pending plus newly accrued rewards for one stream at the given accumulator value
+    pub fn earned(&self, index: usize, reward_per_token: u128) -> Result<u64> {
+        let reward = &self.rewards[index];
+        let delta = reward_per_token
+            .checked_sub(reward.reward_per_token_paid)
+            .ok_or(StakingError::MathOverflow)?;
+        let accrued = math::earned(self.effective_amount, delta)?;
+        Ok(reward
+            .pending_rewards
+            .checked_add(accrued)
+            .ok_or(StakingError::MathOverflow)?)
+    }
+
+    // Moves accrued rewards into `pending_rewards`; the pool must be updated first
+    pub fn settle(&mut self, pool: &StakePool) -> Result<()> {
+        for (index, stream) in pool.active_streams().iter().enumerate() {
+            self.rewards[index].pending_rewards = self.earned(index, stream.reward_per_token_stored)?;
+            self.rewards[index].reward_per_token_paid = stream.reward_per_token_stored;
+        }
+        Ok(())
+    }
+}
+
+// Moves tokens out of a vault owned by the pool PDA. With a transfer-fee mint
+// the recipient gets `amount` less the fee.
+fn transfer_from_pool<'info>(
+    pool: &Account<'info, StakePool>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    amount: u64,
+) -> Result<()> {
+    let seeds = pool.signer_seeds();
+    let signer = &[&seeds[..]];
+    let cpi_accounts = TransferChecked {
+        from: from.to_account_info(),
+        mint: mint.to_account_info(),
+        to: to.to_account_info(),
+        authority: pool.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer);
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)
+}
+
+// Amount that arrives when `amount` of `mint` is transferred in the current
+// epoch, after any Token-2022 transfer fee
+fn amount_after_fee(mint: &InterfaceAccount<Mint>, amount: u64) -> Result<u64> {
+    let info = mint.to_account_info();
+    if *info.owner != token_2022::ID {
+        return Ok(amount);
+    }
+    let data = info.try_borrow_data()?;
+    let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+    let fee = match state.get_extension::<TransferFeeConfig>() {
+        Ok(config) => config
+            .calculate_epoch_fee(Clock::get()?.epoch, amount)
+            .ok_or(StakingError::MathOverflow)?,
+        Err(_) => 0,
+    };
+    Ok(amount - fee)
+}
+
+// Refuses Token-2022 mints with extensions the vaults cannot live with: ones
+// that let a third party move or freeze pool funds, or that need extra
+// accounts on every transfer
+fn check_mint_extensions(mint: &InterfaceAccount<Mint>) -> Result<()> {
+    let info = mint.to_account_info();
+    if *info.owner != token_2022::ID {
+        return Ok(());
+    }
+    let data = info.try_borrow_data()?;
+    let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+    for extension in state.get_extension_types()? {
+        require!(
+            matches!(
+                extension,
+                ExtensionType::TransferFeeConfig
+                    | ExtensionType::MintCloseAuthority
+                    | ExtensionType::InterestBearingConfig
+                    | ExtensionType::MetadataPointer
+                    | ExtensionType::TokenMetadata
+            ),
+            StakingError::UnsupportedMintExtension
+        );
+    }
+    Ok(())
+}
+
+// Moves stream 0's rewards since the last harvest into a liquid pool's stake
+// vault, less the reward fee, and adds what arrives to the pool's stake
+fn harvest_liquid<'info>(
+    pool: &mut Account<'info, StakePool>,
+    reward_vault: &InterfaceAccount<'info, TokenAccount>,
+    stake_vault: &InterfaceAccount<'info, TokenAccount>,
+    fee_account: &Option<InterfaceAccount<'info, TokenAccount>>,
+    token_mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    now: i64,
+) -> Result<()> {
+    let (reward, fee) = pool.harvest_liquid(now)?;
+    pay_compound_fee(pool, reward_vault, fee_account, token_mint, token_program, fee)?;
+    if reward == 0 {
+        return Ok(());
+    }
+    transfer_from_pool(pool, reward_vault, stake_vault, token_mint, token_program, reward)?;
+    let received = amount_after_fee(token_mint, reward)?;
+    pool.add_liquid_rewards(received)?;
+
+    emit!(LiquidRewardsHarvested {
+        version: EVENT_SCHEMA_VERSION,
+        stake_pool: pool.key(),
+        amount: received,
+        fee,
+        total_staked: pool.total_staked,
+        timestamp: now,
+    });
+    Ok(())
+}
+
+// Sends an early-exit penalty out of the stake vault to wherever the pool routes it
+fn route_early_exit_penalty(
+    accounts: &UnstakeTokens,
+    destination: PenaltyDestination,
+    penalty: u64,
+) -> Result<()> {
+    let pool = &accounts.stake_pool;
+    match destination {
+        PenaltyDestination::Treasury => {
+            let treasury = accounts
+                .treasury
+                .as_ref()
+                .ok_or(StakingError::MissingPenaltyAccount)?;
+            transfer_from_pool(
+                pool,
+                &accounts.pool_token_account,
+                treasury,
+                &accounts.token_mint,
+                &accounts.token_program,
+                penalty,
+            )
+        }
+        PenaltyDestination::Burn => {
+            let seeds = pool.signer_seeds();
+            let signer = &[&seeds[..]];
+            let cpi_accounts = Burn {
+                mint: accounts.token_mint.to_account_info(),
+                from: accounts.pool_token_account.to_account_info(),
+                authority: pool.to_account_info(),
+            };
+            let cpi_program = accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token_interface::burn(cpi_ctx, penalty)
+        }
+        // Stream 0 pays the staking mint, so the penalty moves to its vault
+        PenaltyDestination::Redistribute => transfer_from_pool(
+            pool,
+            &accounts.pool_token_account,
+            &accounts.reward_vault,
+            &accounts.token_mint,
+            &accounts.token_program,
+            penalty,
+        ),
+    }
+}
+
+// Pays out settled rewards for every stream, less the protocol fee, and
+// returns the amounts sent to the user and to the fee recipient per stream.
+// Stream 0 uses the named accounts; each further stream expects a
+// (reward_vault, reward_mint, user_reward_account) triple in `remaining_accounts`,
+// followed by the fee recipient's account for that mint when the pool charges a fee.
+#[allow(clippy::too_many_arguments)]
+fn pay_rewards<'info>(
+    pool: &Account<'info, StakePool>,
+    user_stake: &mut UserStake,
+    reward_vault: &InterfaceAccount<'info, TokenAccount>,
+    reward_mint: &InterfaceAccount<'info, Mint>,
+    user_reward_account: &InterfaceAccount<'info, TokenAccount>,
+    fee_account: &Option<InterfaceAccount<'info, TokenAccount>>,
+    remaining_accounts: &'info [AccountInfo<'info>],
+    token_program: &Interface<'info, TokenInterface>,
+) -> Result<([u64; MAX_REWARD_STREAMS], [u64; MAX_REWARD_STREAMS])> {
+    let mut paid = [0; MAX_REWARD_STREAMS];
+    let mut fees = [0; MAX_REWARD_STREAMS];
+    let stride = if pool.reward_fee_bps > 0 { 4 } else { 3 };
+    let extra_streams = pool.reward_stream_count as usize - 1;
+    require!(
+        remaining_accounts.len() >= extra_streams * stride,
+        StakingError::MissingRewardAccounts
+    );
+
+    let reward = user_stake.rewards[0].pending_rewards;
+    user_stake.rewards[0].pending_rewards = 0;
+    if reward > 0 {
+        let fee = pool.reward_fee(reward)?;
+        if fee > 0 {
+            let fee_account = fee_account.as_ref().ok_or(StakingError::MissingFeeAccount)?;
+            check_fee_account(pool, fee_account, &reward_mint.key())?;
+            transfer_from_pool(pool, reward_vault, fee_account, reward_mint, token_program, fee)?;
+        }
+        transfer_from_pool(
+            pool,
+            reward_vault,
+            user_reward_account,
+            reward_mint,
+            token_program,
+            reward - fee,
+        )?;
+        paid[0] = reward - fee;
+        fees[0] = fee;
+    }
+
+    for (index, accounts) in remaining_accounts[..extra_streams * stride]
+        .chunks(stride)
+        .enumerate()
+    {
+        let index = index + 1;
+        let reward = user_stake.rewards[index].pending_rewards;
+        if reward == 0 {
+            continue;
+        }
+
+        let stream = &pool.reward_streams[index];
+        let vault = InterfaceAccount::<TokenAccount>::try_from(&accounts[0])?;
+        let mint = InterfaceAccount::<Mint>::try_from(&accounts[1])?;
+        let destination = InterfaceAccount::<TokenAccount>::try_from(&accounts[2])?;
+        require_keys_eq!(vault.key(), stream.reward_vault, StakingError::InvalidRewardVault);
+        require_keys_eq!(mint.key(), stream.reward_mint, StakingError::InvalidRewardMint);
+        require_keys_eq!(destination.mint, stream.reward_mint, StakingError::InvalidRewardMint);
+
+        user_stake.rewards[index].pending_rewards = 0;
+        let fee = pool.reward_fee(reward)?;
+        if fee > 0 {
+            let fee_account = InterfaceAccount::<TokenAccount>::try_from(&accounts[3])?;
+            check_fee_account(pool, &fee_account, &stream.reward_mint)?;
+            transfer_from_pool(pool, &vault, &fee_account, &mint, token_program, fee)?;
+        }
+        transfer_from_pool(pool, &vault, &destination, &mint, token_program, reward - fee)?;
+        paid[index] = reward - fee;
+        fees[index] = fee;
+    }
+    Ok((paid, fees))
+}
+
+// Checked when the treasury is set, so a bad account cannot block every
+// penalised exit later on
+fn check_treasury_account(
+    pool: &StakePool,
+    treasury: &Option<InterfaceAccount<TokenAccount>>,
+) -> Result<()> {
+    if pool.early_exit_penalty.destination != PenaltyDestination::Treasury {
+        return Ok(());
+    }
+    let treasury = treasury.as_ref().ok_or(StakingError::MissingPenaltyAccount)?;
+    require!(
+        treasury.key() == pool.early_exit_penalty.treasury && treasury.mint == pool.token_mint,
+        StakingError::InvalidPenaltyConfig
+    );
+    Ok(())
+}
+
+fn check_fee_account(
+    pool: &StakePool,
+    fee_account: &InterfaceAccount<TokenAccount>,
+    mint: &Pubkey,
+) -> Result<()> {
+    require!(
+        fee_account.owner == pool.fee_recipient && fee_account.mint == *mint,
+        StakingError::InvalidFeeAccount
+    );
+    Ok(())
+}
+
+// Sends the protocol's cut of compounded rewards, which are in the staking mint
+fn pay_compound_fee<'info>(
+    pool: &Account<'info, StakePool>,
+    reward_vault: &InterfaceAccount<'info, TokenAccount>,
+    fee_account: &Option<InterfaceAccount<'info, TokenAccount>>,
+    token_mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    fee: u64,
+) -> Result<()> {
+    if fee == 0 {
+        return Ok(());
+    }
+    let fee_account = fee_account.as_ref().ok_or(StakingError::MissingFeeAccount)?;
+    check_fee_account(pool, fee_account, &token_mint.key())?;
+    transfer_from_pool(pool, reward_vault, fee_account, token_mint, token_program, fee)
+}
+
+fn emit_staked(
+    pool: &Account<StakePool>,
+    user_stake: &Account<UserStake>,
+    owner: Pubkey,
+    amount: u64,
+    now: i64,
+) {
+    emit!(Staked {
+        version: EVENT_SCHEMA_VERSION,
+        stake_pool: pool.key(),
+        user_stake: user_stake.key(),
+        owner,
+        amount,
+        user_amount: user_stake.amount,
+        total_staked: pool.total_staked,
+        lock_end: user_stake.lock_end,
+        reward_indexes: pool.reward_indexes(),
+        timestamp: now,
+    });
+}
+
+fn emit_rewards_claimed(
+    pool: &Account<StakePool>,
+    user_stake: &Account<UserStake>,
+    owner: Pubkey,
+    amounts: [u64; MAX_REWARD_STREAMS],
+    fees: [u64; MAX_REWARD_STREAMS],
+    now: i64,
+) {
+    emit!(RewardsClaimed {
+        version: EVENT_SCHEMA_VERSION,
+        stake_pool: pool.key(),
+        user_stake: user_stake.key(),
+        owner,
+        amounts,
+        fees,
+        reward_indexes: pool.reward_indexes(),
+        timestamp: now,
+    });
 }
 
-fn calculate_reward(pool: &mut StakePool, user_stake: &UserStake) -> Result<u64> {
-    let current_time = Clock::get()?.unix_timestamp;
-    let time_passed = (current_time - user_stake.staked_at) as u64;
-    
-    // Simple reward calculation: stake_amount * rate * time
-    let reward = (user_stake.amount * pool.stake_rate * time_passed) / 1000; // Adjust denominator as needed
-    
-    Ok(reward)
+fn emit_compounded(
+    pool: &Account<StakePool>,
+    user_stake: &Account<UserStake>,
+    reward: u64,
+    fee: u64,
+    restaked: u64,
+    now: i64,
+) {
+    emit!(Compounded {
+        version: EVENT_SCHEMA_VERSION,
+        stake_pool: pool.key(),
+        user_stake: user_stake.key(),
+        reward,
+        fee,
+        restaked,
+        user_amount: user_stake.amount,
+        total_staked: pool.total_staked,
+        reward_index: pool.reward_streams[0].reward_per_token_stored,
+        timestamp: now,
+    });
 }
 
 #[error_code]
 pub enum StakingError {
     #[msg("Insufficient stake amount")]
     InsufficientStake,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+    #[msg("Pool already has the maximum number of reward streams")]
+    TooManyRewardStreams,
+    #[msg("Reward stream must end after it starts")]
+    InvalidRewardWindow,
+    #[msg("Reward stream index out of range")]
+    InvalidRewardStream,
+    #[msg("Missing reward accounts for one or more streams")]
+    MissingRewardAccounts,
+    #[msg("Reward vault does not match the stream")]
+    InvalidRewardVault,
+    #[msg("Reward account mint does not match the stream")]
+    InvalidRewardMint,
+    #[msg("Campaign budget is too small for its duration")]
+    BudgetTooSmall,
+    #[msg("A campaign is already queued for this stream")]
+    CampaignAlreadyQueued,
+    #[msg("Queued campaign must start after the current one ends")]
+    CampaignOverlap,
+    #[msg("Lock tier index out of range")]
+    InvalidLockTier,
+    #[msg("Invalid lock tier configuration")]
+    InvalidLockTierConfig,
+    #[msg("Stake is still locked")]
+    StakeLocked,
+    #[msg("Unbonding period is negative or too long")]
+    InvalidUnbondingPeriod,
+    #[msg("Pool has a cooldown; use request_unstake")]
+    UnbondingRequired,
+    #[msg("Amount must be greater than zero")]
+    InvalidAmount,
+    #[msg("No unstake request pending")]
+    NothingToWithdraw,
+    #[msg("Unstake cooldown has not ended")]
+    CooldownActive,
+    #[msg("Invalid early-exit penalty configuration")]
+    InvalidPenaltyConfig,
+    #[msg("Missing the account that receives the early-exit penalty")]
+    MissingPenaltyAccount,
+    #[msg("Compounding needs stream 0 to pay out the staking mint")]
+    CompoundUnsupported,
+    #[msg("No rewards to compound")]
+    NothingToCompound,
+    #[msg("Position has not opted into auto-compounding")]
+    AutoCompoundDisabled,
+    #[msg("Pool must be empty")]
+    PoolNotEmpty,
+    #[msg("Liquid pools cannot have locked tiers or an unbonding period")]
+    LiquidPoolLocks,
+    #[msg("Pool does not issue receipt tokens")]
+    NotLiquidPool,
+    #[msg("Pool only accepts stakes through open_position")]
+    NftPositionsOnly,
+    #[msg("Pool does not use position NFTs")]
+    NotNftPool,
+    #[msg("Pool cannot be both liquid and NFT-based")]
+    PositionModeConflict,
+    #[msg("Position NFT name, symbol or URI is too long")]
+    PositionMetadataTooLong,
+    #[msg("Signer does not control this position")]
+    Unauthorized,
+    #[msg("Timestamp is before the last voting power checkpoint")]
+    TimestampBeforeCheckpoint,
+    #[msg("Pool is not linked to a governance realm")]
+    GovernanceNotConfigured,
+    #[msg("Invalid authority; use renounce_authority to drop it")]
+    InvalidAuthority,
+    #[msg("No authority transfer is pending")]
+    NoPendingAuthority,
+    #[msg("Signer is not the pending authority")]
+    NotPendingAuthority,
+    #[msg("Pool is paused")]
+    PoolPaused,
+    #[msg("Pool is in emergency mode")]
+    EmergencyMode,
+    #[msg("Pool is not in emergency mode")]
+    NotEmergencyMode,
+    #[msg("Minimum stake exceeds a stake cap")]
+    InvalidStakeLimits,
+    #[msg("Stake is below the pool minimum")]
+    StakeBelowMinimum,
+    #[msg("Stake would exceed the per-user cap")]
+    UserStakeCapExceeded,
+    #[msg("Stake would exceed the pool cap")]
+    PoolStakeCapExceeded,
+    #[msg("Wallet is not on the pool allowlist")]
+    NotAllowlisted,
+    #[msg("Stake would exceed the wallet's allowlist cap")]
+    AllowlistCapExceeded,
+    #[msg("Mint uses a Token-2022 extension the pool cannot support")]
+    UnsupportedMintExtension,
+    #[msg("Mint belongs to a different token program than the pool")]
+    TokenProgramMismatch,
+    #[msg("Reward fee exceeds the maximum or has no recipient")]
+    InvalidRewardFee,
+    #[msg("Pool charges a reward fee but no fee account was provided")]
+    MissingFeeAccount,
+    #[msg("Fee account does not belong to the fee recipient or has the wrong mint")]
+    InvalidFeeAccount,
+    #[msg("Position has no lock boost to expire")]
+    LockNotBoosted,
+    #[msg("Liquid and NFT positions can change owner and cannot vote")]
+    TransferablePositions,
+    #[msg("Stream has no running or scheduled campaign")]
+    NoActiveCampaign,
+    #[msg("Reward rate is zero or too high for the remaining budget")]
+    InvalidRewardRate,
+    #[msg("Stream has no unallocated rewards to reclaim")]
+    NothingToReclaim,
+    #[msg("Lock tier would end before the position's current lock")]
+    LockTierTooShort,
+    #[msg("Pool only accepts stakes through stake_liquid")]
+    LiquidStakesOnly,
+    #[msg("Liquid pools pay a single reward stream in the staking mint")]
+    LiquidPoolRewards,
+    #[msg("Account is not an SPL Governance realm")]
+    InvalidRealm,
+    #[msg("Signer is not the realm authority")]
+    InvalidRealmAuthority,
+    #[msg("Realm does not govern with the pool's staking mint")]
+    InvalidGoverningMint,
+    #[msg("Pool is already linked to a realm")]
+    RealmAlreadySet,
+    #[msg("Unbonding period must outlast the realm's voting period")]
+    UnbondingShorterThanVoting,
 }
@@ -0,0 +1,155 @@
+// math.rs
+//
+// Fixed-point arithmetic for rewards, boosts and penalties. Products are
+// formed in 192 bits so no intermediate can overflow, every division states
+// which way it rounds, and results that do not fit return MathOverflow.
+// Amounts paid to users round down and amounts users owe round up, so the
+// pool never owes more than it holds.
+
+use anchor_lang::prelude::*;
+
+use crate::{StakingError, BPS_DENOMINATOR, REWARD_PRECISION};
+pub use wide::U192;
+
+// Kept apart from the prelude, whose `Result` alias the macro's output cannot use
+mod wide {
+    // construct_uint! expands to code these lints flag
+    #![allow(
+        clippy::assign_op_pattern,
+        clippy::manual_div_ceil,
+        clippy::manual_range_contains,
+        clippy::ptr_offset_with_cast
+    )]
+
+    uint::construct_uint! {
+        pub struct U192(3);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+// `a * b / denominator`, rounded as asked
+pub fn mul_div(a: u128, b: u128, denominator: u128, rounding: Rounding) -> Result<u128> {
+    require!(denominator != 0, StakingError::MathOverflow);
+    let product = U192::from(a)
+        .checked_mul(U192::from(b))
+        .ok_or(StakingError::MathOverflow)?;
+    let denominator = U192::from(denominator);
+    let (mut quotient, remainder) = product.div_mod(denominator);
+    if rounding == Rounding::Up && !remainder.is_zero() {
+        quotient += U192::one();
+    }
+    require!(quotient <= U192::from(u128::MAX), StakingError::MathOverflow);
+    Ok(quotient.as_u128())
+}
+
+pub fn to_u64(value: u128) -> Result<u64> {
+    Ok(u64::try_from(value).map_err(|_| StakingError::MathOverflow)?)
+}
+
+// `amount` scaled by a basis-point factor
+pub fn apply_bps(amount: u64, bps: u64, rounding: Rounding) -> Result<u64> {
+    to_u64(mul_div(amount as u128, bps as u128, BPS_DENOMINATOR as u128, rounding)?)
+}
+
+// Accumulator increase for `amount` shared over `total_effective_stake`;
+// rounds down so the shares never add up to more than `amount`
+pub fn reward_per_token(amount: u128, total_effective_stake: u64) -> Result<u128> {
+    mul_div(amount, REWARD_PRECISION, total_effective_stake as u128, Rounding::Down)
+}
+
+// A position's share of an accumulator increase, rounded down
+pub fn earned(effective_amount: u64, reward_per_token_delta: u128) -> Result<u64> {
+    to_u64(mul_div(
+        effective_amount as u128,
+        reward_per_token_delta,
+        REWARD_PRECISION,
+        Rounding::Down,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mul_div_rounds_as_asked() {
+        assert_eq!(mul_div(10, 1, 3, Rounding::Down).unwrap(), 3);
+        assert_eq!(mul_div(10, 1, 3, Rounding::Up).unwrap(), 4);
+        assert_eq!(mul_div(9, 1, 3, Rounding::Up).unwrap(), 3);
+        assert_eq!(mul_div(0, u128::MAX, 7, Rounding::Up).unwrap(), 0);
+    }
+
+    #[test]
+    fn mul_div_keeps_wide_intermediates() {
+        let max = u64::MAX as u128;
+        assert_eq!(mul_div(max, max, max, Rounding::Down).unwrap(), max);
+        assert_eq!(mul_div(max, max, max, Rounding::Up).unwrap(), max);
+        assert_eq!(mul_div(u128::MAX, max, max, Rounding::Down).unwrap(), u128::MAX);
+        assert!(mul_div(u128::MAX, u128::MAX, u128::MAX, Rounding::Up).is_err());
+    }
+
+    #[test]
+    fn mul_div_rejects_overflow() {
+        assert!(mul_div(u128::MAX, 2, 1, Rounding::Down).is_err());
+        assert!(mul_div(u128::MAX, u128::MAX, 1, Rounding::Down).is_err());
+        assert!(mul_div(1, 1, 0, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn apply_bps_at_u64_extremes() {
+        let bps = BPS_DENOMINATOR;
+        assert_eq!(apply_bps(u64::MAX, bps, Rounding::Down).unwrap(), u64::MAX);
+        assert_eq!(apply_bps(u64::MAX, 0, Rounding::Up).unwrap(), 0);
+        assert!(apply_bps(u64::MAX, bps + 1, Rounding::Down).is_err());
+        assert_eq!(apply_bps(1, 1, Rounding::Down).unwrap(), 0);
+        assert_eq!(apply_bps(1, 1, Rounding::Up).unwrap(), 1);
+    }
+
+    #[test]
+    fn reward_per_token_at_u64_extremes() {
+        let max = u64::MAX as u128;
+        // A full u64 emission over a single unit of stake still fits
+        assert_eq!(reward_per_token(max, 1).unwrap(), max * REWARD_PRECISION);
+        assert_eq!(reward_per_token(max, u64::MAX).unwrap(), REWARD_PRECISION);
+        assert_eq!(reward_per_token(1, u64::MAX).unwrap(), 0);
+        assert!(reward_per_token(u128::MAX, 1).is_err());
+        assert!(reward_per_token(1, 0).is_err());
+    }
+
+    #[test]
+    fn earned_at_u64_extremes() {
+        let max = u64::MAX as u128;
+        assert_eq!(earned(u64::MAX, REWARD_PRECISION).unwrap(), u64::MAX);
+        assert_eq!(earned(1, max * REWARD_PRECISION).unwrap(), u64::MAX);
+        assert!(earned(u64::MAX, 2 * REWARD_PRECISION).is_err());
+        // The widest product still fits in 192 bits; only the result overflows
+        assert!(earned(u64::MAX, u128::MAX).is_err());
+        assert_eq!(earned(u64::MAX, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn rounding_never_overpays() {
+        // Three equal stakes splitting an emission that does not divide evenly
+        let stake = 1_000_000_007;
+        let emitted = 1_000_000_000_000_000_001;
+        let index = reward_per_token(emitted, 3 * stake).unwrap();
+        let paid = 3 * earned(stake, index).unwrap() as u128;
+        // At most one unit of dust per position stays in the vault
+        assert!(paid <= emitted);
+        assert!(emitted - paid <= 3);
+    }
+
+    #[test]
+    fn small_stakes_in_large_pools_still_earn() {
+        // 1 token of 6 decimals in a pool of a billion tokens, at 1 token per second for a day
+        let total = 1_000_000_000 * 1_000_000;
+        let emitted = 86_400 * 1_000_000;
+        let index = reward_per_token(emitted, total).unwrap();
+        assert_eq!(earned(1_000_000, index).unwrap(), 86);
+    }
+}
@@ -0,0 +1,447 @@
+// common/mod.rs
+//
+// In-process harness: one pool per test on solana-program-test, driven through
+// the staking-client instruction builders. The Metaplex metadata program is
+// not built here, so a no-op stands in for it and position NFTs get no metadata.
+// Pools use SPL Token unless built with Harness::token_2022, whose mints can
+// carry extensions.
+#![allow(dead_code)]
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{entrypoint::ProgramResult, program_pack::Pack, system_instruction};
+use anchor_lang::AccountDeserialize;
+use anchor_spl::metadata::mpl_token_metadata;
+use anchor_spl::token::spl_token;
+use anchor_spl::token_2022::spl_token_2022;
+use solana_program_test::{processor, BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    instruction::{Instruction, InstructionError},
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use spl_associated_token_account::instruction::create_associated_token_account;
+use spl_token_2022::extension::{transfer_fee, transfer_hook, ExtensionType, StateWithExtensions};
+use staking_client::{pda, PoolKeys, Staker};
+use staking_program::{
+    LockTier, PenaltyConfig, PenaltyCurve, PenaltyDestination, StakePool, UserStake,
+    BPS_DENOMINATOR,
+};
+
+// Later than any genesis clock, so the runtime never moves time backwards
+pub const START: i64 = 2_000_000_000;
+
+// Anchor's entrypoint ties the account slice to its contents' lifetime, which
+// the program-test processor signature cannot express
+fn entry(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let accounts = Box::leak(Box::new(accounts.to_vec()));
+    staking_program::entry(program_id, accounts, data)
+}
+
+// Accepts every metadata instruction without writing anything
+fn metadata_stub(_program_id: &Pubkey, _accounts: &[AccountInfo], _data: &[u8]) -> ProgramResult {
+    Ok(())
+}
+
+pub fn unlocked() -> Vec<LockTier> {
+    vec![LockTier {
+        duration: 0,
+        multiplier_bps: BPS_DENOMINATOR as u16,
+    }]
+}
+
+pub fn no_penalty() -> PenaltyConfig {
+    PenaltyConfig {
+        curve: PenaltyCurve::None,
+        penalty_bps: 0,
+        destination: PenaltyDestination::Burn,
+        treasury: Pubkey::default(),
+    }
+}
+
+// Token-2022 extensions a test mint can be created with
+#[derive(Clone, Copy)]
+pub enum MintExtension {
+    TransferFee { bps: u16, maximum_fee: u64 },
+    TransferHook,
+    PermanentDelegate,
+}
+
+impl MintExtension {
+    fn extension_type(self) -> ExtensionType {
+        match self {
+            Self::TransferFee { .. } => ExtensionType::TransferFeeConfig,
+            Self::TransferHook => ExtensionType::TransferHook,
+            Self::PermanentDelegate => ExtensionType::PermanentDelegate,
+        }
+    }
+
+    fn instruction(self, mint: &Pubkey, authority: &Pubkey) -> Instruction {
+        let program = &spl_token_2022::ID;
+        match self {
+            Self::TransferFee { bps, maximum_fee } => {
+                transfer_fee::instruction::initialize_transfer_fee_config(
+                    program,
+                    mint,
+                    Some(authority),
+                    Some(authority),
+                    bps,
+                    maximum_fee,
+                )
+                .unwrap()
+            }
+            Self::TransferHook => {
+                transfer_hook::instruction::initialize(
+                    program,
+                    mint,
+                    Some(*authority),
+                    Some(Pubkey::new_unique()),
+                )
+                .unwrap()
+            }
+            Self::PermanentDelegate => {
+                spl_token_2022::instruction::initialize_permanent_delegate(program, mint, authority)
+                    .unwrap()
+            }
+        }
+    }
+}
+
+pub struct TestStaker {
+    pub keypair: Keypair,
+    pub staker: Staker,
+}
+
+pub struct Harness {
+    pub context: ProgramTestContext,
+    pub token_program: Pubkey,
+    pub token_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub keys: PoolKeys,
+}
+
+impl Harness {
+    pub async fn new() -> Self {
+        Self::with_config(false, unlocked(), 0, no_penalty()).await
+    }
+
+    // `same_mint` pays stream 0 in the staking mint, as compounding requires
+    pub async fn with_config(
+        same_mint: bool,
+        lock_tiers: Vec<LockTier>,
+        unbonding_period: i64,
+        penalty: PenaltyConfig,
+    ) -> Self {
+        Self::build(spl_token::ID, &[], same_mint, lock_tiers, unbonding_period, penalty).await
+    }
+
+    // A Token-2022 pool whose staking mint carries `extensions`; a separate
+    // reward mint has none
+    pub async fn token_2022(same_mint: bool, extensions: &[MintExtension]) -> Self {
+        Self::build(spl_token_2022::ID, extensions, same_mint, unlocked(), 0, no_penalty()).await
+    }
+
+    async fn build(
+        token_program: Pubkey,
+        extensions: &[MintExtension],
+        same_mint: bool,
+        lock_tiers: Vec<LockTier>,
+        unbonding_period: i64,
+        penalty: PenaltyConfig,
+    ) -> Self {
+        let mut program_test =
+            ProgramTest::new("staking_program", staking_program::ID, processor!(entry));
+        program_test.add_program(
+            "mpl_token_metadata",
+            mpl_token_metadata::ID,
+            processor!(metadata_stub),
+        );
+        let mut context = program_test.start_with_context().await;
+        let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp = START;
+        context.set_sysvar(&clock);
+
+        let mut harness = Self {
+            context,
+            token_program,
+            token_mint: Pubkey::default(),
+            reward_mint: Pubkey::default(),
+            keys: PoolKeys {
+                stake_pool: Pubkey::default(),
+                token_mint: Pubkey::default(),
+                token_program,
+                stake_vault: Pubkey::default(),
+                receipt_mint: None,
+                treasury: None,
+                fee_recipient: None,
+                reward_streams: vec![],
+            },
+        };
+        harness.token_mint = harness.create_mint_with(extensions).await;
+        harness.reward_mint = if same_mint {
+            harness.token_mint
+        } else {
+            harness.create_mint().await
+        };
+
+        let authority = harness.authority();
+        harness
+            .process(
+                &[PoolKeys::initialize_stake_pool(
+                    harness.token_mint,
+                    harness.reward_mint,
+                    authority,
+                    token_program,
+                    lock_tiers,
+                    unbonding_period,
+                    penalty,
+                )],
+                &[],
+            )
+            .await
+            .unwrap();
+        harness.refresh_keys().await;
+        harness
+    }
+
+    pub fn authority(&self) -> Pubkey {
+        self.context.payer.pubkey()
+    }
+
+    pub async fn refresh_keys(&mut self) {
+        let stake_pool = pda::stake_pool(&self.token_mint);
+        let pool = self.pool().await;
+        self.keys = PoolKeys::from_pool(stake_pool, &pool, self.token_program);
+    }
+
+    pub async fn process(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> std::result::Result<(), BanksClientError> {
+        let blockhash = self.context.get_new_latest_blockhash().await.unwrap();
+        let mut all_signers = vec![&self.context.payer];
+        all_signers.extend_from_slice(signers);
+        let tx = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.context.payer.pubkey()),
+            &all_signers,
+            blockhash,
+        );
+        self.context.banks_client.process_transaction(tx).await
+    }
+
+    // Runs a view instruction and decodes its u64 return value
+    pub async fn simulate_u64(&mut self, instruction: Instruction) -> u64 {
+        let blockhash = self.context.get_new_latest_blockhash().await.unwrap();
+        let tx = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&self.context.payer.pubkey()),
+            &[&self.context.payer],
+            blockhash,
+        );
+        let simulation = self.context.banks_client.simulate_transaction(tx).await.unwrap();
+        simulation.result.unwrap().unwrap();
+        let return_data = simulation
+            .simulation_details
+            .and_then(|details| details.return_data)
+            .expect("return data");
+        u64::from_le_bytes(return_data.data[..8].try_into().unwrap())
+    }
+
+    pub async fn warp_to(&mut self, timestamp: i64) {
+        let mut clock: Clock = self.context.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp = timestamp;
+        self.context.set_sysvar(&clock);
+    }
+
+    pub async fn create_mint(&mut self) -> Pubkey {
+        self.create_mint_with(&[]).await
+    }
+
+    // Extensions need a Token-2022 harness
+    pub async fn create_mint_with(&mut self, extensions: &[MintExtension]) -> Pubkey {
+        let mint = Keypair::new();
+        let authority = self.authority();
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let extension_types: Vec<_> = extensions.iter().map(|e| e.extension_type()).collect();
+        let len = if self.token_program == spl_token_2022::ID {
+            ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(
+                &extension_types,
+            )
+            .unwrap()
+        } else {
+            assert!(extensions.is_empty(), "SPL Token mints have no extensions");
+            spl_token::state::Mint::LEN
+        };
+
+        let mut instructions = vec![system_instruction::create_account(
+            &authority,
+            &mint.pubkey(),
+            rent.minimum_balance(len),
+            len as u64,
+            &self.token_program,
+        )];
+        for extension in extensions {
+            instructions.push(extension.instruction(&mint.pubkey(), &authority));
+        }
+        instructions.push(
+            spl_token_2022::instruction::initialize_mint(
+                &self.token_program,
+                &mint.pubkey(),
+                &authority,
+                None,
+                6,
+            )
+            .unwrap(),
+        );
+        self.process(&instructions, &[&mint]).await.unwrap();
+        mint.pubkey()
+    }
+
+    pub async fn create_token_account(&mut self, owner: &Pubkey, mint: &Pubkey) -> Pubkey {
+        let authority = self.authority();
+        self.process(
+            &[create_associated_token_account(&authority, owner, mint, &self.token_program)],
+            &[],
+        )
+        .await
+        .unwrap();
+        pda::token_account(owner, mint, &self.token_program)
+    }
+
+    pub async fn mint_to(&mut self, mint: &Pubkey, account: &Pubkey, amount: u64) {
+        let authority = self.authority();
+        self.process(
+            &[spl_token_2022::instruction::mint_to(
+                &self.token_program,
+                mint,
+                account,
+                &authority,
+                &[],
+                amount,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+        .unwrap();
+    }
+
+    // A funded wallet with token accounts for the staking mint and every stream
+    pub async fn new_staker(&mut self, balance: u64) -> TestStaker {
+        let keypair = Keypair::new();
+        let owner = keypair.pubkey();
+        let authority = self.authority();
+        self.process(
+            &[system_instruction::transfer(&authority, &owner, 1_000_000_000)],
+            &[],
+        )
+        .await
+        .unwrap();
+
+        let token_mint = self.token_mint;
+        let token_account = self.create_token_account(&owner, &token_mint).await;
+        self.mint_to(&token_mint, &token_account, balance).await;
+        let mut reward_accounts = vec![];
+        for (mint, _) in self.keys.reward_streams.clone() {
+            if mint == token_mint {
+                reward_accounts.push(token_account);
+            } else {
+                reward_accounts.push(self.create_token_account(&owner, &mint).await);
+            }
+        }
+        let receipt_account = match self.keys.receipt_mint {
+            Some(mint) => Some(self.create_token_account(&owner, &mint).await),
+            None => None,
+        };
+
+        TestStaker {
+            keypair,
+            staker: Staker {
+                authority: owner,
+                token_account,
+                reward_accounts,
+                receipt_account,
+                position: None,
+            },
+        }
+    }
+
+    pub async fn stake(
+        &mut self,
+        who: &TestStaker,
+        amount: u64,
+        lock_tier: u8,
+    ) -> std::result::Result<(), BanksClientError> {
+        let ix = self.keys.stake_tokens(&who.staker, amount, lock_tier, None);
+        self.process(&[ix], &[&who.keypair]).await
+    }
+
+    // Funds a campaign for `stream_index` from the authority's own token account
+    pub async fn fund(
+        &mut self,
+        stream_index: u8,
+        budget: u64,
+        start: i64,
+        end: i64,
+    ) -> std::result::Result<(), BanksClientError> {
+        let (mint, _) = self.keys.reward_streams[stream_index as usize];
+        let authority = self.authority();
+        let funder = pda::token_account(&authority, &mint, &self.token_program);
+        if self.context.banks_client.get_account(funder).await.unwrap().is_none() {
+            self.create_token_account(&authority, &mint).await;
+        }
+        self.mint_to(&mint, &funder, budget).await;
+        let ix = self
+            .keys
+            .queue_reward_campaign(authority, funder, stream_index, budget, start, end);
+        self.process(&[ix], &[]).await
+    }
+
+    pub async fn pool(&mut self) -> StakePool {
+        let address = pda::stake_pool(&self.token_mint);
+        self.account(&address).await
+    }
+
+    pub async fn user_stake(&mut self, who: &TestStaker) -> UserStake {
+        let address = self.keys.user_stake(&who.staker);
+        self.account(&address).await
+    }
+
+    pub async fn account<T: AccountDeserialize>(&mut self, address: &Pubkey) -> T {
+        let account = self
+            .context
+            .banks_client
+            .get_account(*address)
+            .await
+            .unwrap()
+            .expect("account exists");
+        T::try_deserialize(&mut &account.data[..]).unwrap()
+    }
+
+    pub async fn balance(&mut self, token_account: &Pubkey) -> u64 {
+        let account = self
+            .context
+            .banks_client
+            .get_account(*token_account)
+            .await
+            .unwrap()
+            .expect("token account exists");
+        StateWithExtensions::<spl_token_2022::state::Account>::unpack(&account.data)
+            .unwrap()
+            .base
+            .amount
+    }
+}
+
+// Asserts that a transaction failed with the given program or Anchor error code
+pub fn assert_error(result: std::result::Result<(), BanksClientError>, code: impl Into<u32>) {
+    let code = code.into();
+    match result {
+        Err(BanksClientError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(actual),
+        ))) => assert_eq!(actual, code, "expected error {code}, got {actual}"),
+        other => panic!("expected error {code}, got {other:?}"),
+    }
+}
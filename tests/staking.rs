@@ -0,0 +1,1661 @@
+// staking.rs
+//
+// End-to-end tests against the compiled program on an in-process runtime
+mod common;
+
+use anchor_lang::error::ErrorCode;
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak;
+use anchor_spl::token::spl_token;
+use anchor_spl::token_2022::spl_token_2022;
+use common::{assert_error, no_penalty, unlocked, Harness, MintExtension, TestStaker, START};
+use solana_program_test::BanksClientError;
+use solana_sdk::account::Account;
+use solana_sdk::signature::{Keypair, Signer};
+use staking_client::{pda, rewards, PoolKeys, Position};
+use staking_program::{
+    AllowlistProof, LockTier, PenaltyConfig, PenaltyCurve, PenaltyDestination, PoolConfigUpdate,
+    PositionNftConfig, RealmConfigHeader, RealmHeader, Registrar, StakingError, UserStake,
+    VoterWeightRecord, WalletStake, BPS_DENOMINATOR, MAX_REWARD_FEE_BPS, MAX_UNBONDING_PERIOD,
+    POSITION_NAME_MAX_LEN, POSITION_SYMBOL_MAX_LEN, POSITION_URI_BASE_MAX_LEN,
+    REALM_V2_ACCOUNT_TYPE, WEEK,
+};
+
+const BUDGET: u64 = 1_000_000;
+const DURATION: i64 = 1_000; // BUDGET over DURATION emits 1_000 per second
+
+fn locked_tiers() -> Vec<LockTier> {
+    vec![
+        LockTier {
+            duration: 0,
+            multiplier_bps: BPS_DENOMINATOR as u16,
+        },
+        LockTier {
+            duration: WEEK,
+            multiplier_bps: 15_000,
+        },
+    ]
+}
+
+fn allowlist_leaf(wallet: &Pubkey, cap: u64) -> [u8; 32] {
+    keccak::hashv(&[wallet.as_ref(), &cap.to_le_bytes()]).0
+}
+
+fn allowlist_node(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+    if a <= b {
+        keccak::hashv(&[&a, &b]).0
+    } else {
+        keccak::hashv(&[&b, &a]).0
+    }
+}
+
+#[tokio::test]
+async fn initialize_sets_up_primary_stream() {
+    let mut h = Harness::new().await;
+    let pool = h.pool().await;
+
+    assert_eq!(pool.authority, h.authority());
+    assert_eq!(pool.token_mint, h.token_mint);
+    assert_eq!(pool.total_staked, 0);
+    assert_eq!(pool.reward_stream_count, 1);
+    assert_eq!(pool.reward_streams[0].reward_mint, h.reward_mint);
+    assert_eq!(
+        pool.reward_streams[0].reward_vault,
+        pda::reward_vault(&h.keys.stake_pool, &h.reward_mint)
+    );
+    assert_eq!(h.balance(&pool.reward_streams[0].reward_vault).await, 0);
+}
+
+#[tokio::test]
+async fn stake_and_unstake_round_trip() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    let stake_vault = h.keys.stake_vault;
+
+    h.stake(&alice, 600, 0).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 400);
+    assert_eq!(h.balance(&stake_vault).await, 600);
+    assert_eq!(h.user_stake(&alice).await.amount, 600);
+    assert_eq!(h.pool().await.total_staked, 600);
+
+    // The vault is released by the pool PDA signing for it
+    let ix = h.keys.unstake_tokens(&alice.staker, 600);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 1_000);
+    assert_eq!(h.balance(&stake_vault).await, 0);
+    assert_eq!(h.user_stake(&alice).await.amount, 0);
+    assert_eq!(h.pool().await.total_staked, 0);
+}
+
+#[tokio::test]
+async fn unstake_checks_balance_and_owner() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(1_000).await;
+    h.stake(&alice, 500, 0).await.unwrap();
+
+    let ix = h.keys.unstake_tokens(&alice.staker, 501);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::InsufficientStake);
+
+    // Bob signs for Alice's position
+    let alice_stake = h.keys.user_stake(&alice.staker);
+    let bob_stake = h.keys.user_stake(&bob.staker);
+    let mut ix = h.keys.unstake_tokens(&bob.staker, 500);
+    for meta in ix.accounts.iter_mut() {
+        if meta.pubkey == bob_stake {
+            meta.pubkey = alice_stake;
+        }
+    }
+    let result = h.process(&[ix], &[&bob.keypair]).await;
+    assert_error(result, StakingError::Unauthorized);
+}
+
+#[tokio::test]
+async fn stake_rejects_wrong_mint_and_tier() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+
+    let mut staker = alice.staker.clone();
+    staker.token_account = staker.reward_accounts[0];
+    let ix = h.keys.stake_tokens(&staker, 100, 0, None);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, ErrorCode::ConstraintRaw);
+
+    let result = h.stake(&alice, 100, 1).await;
+    assert_error(result, StakingError::InvalidLockTier);
+}
+
+#[tokio::test]
+async fn rewards_accrue_over_time() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+
+    h.warp_to(START + DURATION / 2).await;
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, BUDGET / 2);
+
+    // Nothing accrues past the end of the campaign
+    h.warp_to(START + DURATION * 2).await;
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, BUDGET);
+    let reward_vault = h.keys.reward_streams[0].1;
+    assert_eq!(h.balance(&reward_vault).await, 0);
+}
+
+#[tokio::test]
+async fn reward_rate_changes_keep_accrued_rewards() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+
+    let ix = h.keys.set_reward_rate(h.authority(), 0, 0);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::InvalidRewardRate);
+    let stranger = Keypair::new();
+    let ix = h.keys.set_reward_rate(stranger.pubkey(), 0, 250);
+    let result = h.process(&[ix], &[&stranger]).await;
+    assert_error(result, ErrorCode::ConstraintHasOne);
+
+    // Half the budget went out at 1_000 per second; the rest now takes 2_000 seconds
+    h.warp_to(START + DURATION / 2).await;
+    let ix = h.keys.set_reward_rate(h.authority(), 0, 250);
+    h.process(&[ix], &[]).await.unwrap();
+    let stream = h.pool().await.reward_streams[0];
+    assert_eq!(stream.reward_rate, 250);
+    assert_eq!(stream.reward_end, START + DURATION / 2 + 2_000);
+
+    h.warp_to(START + DURATION / 2 + 1_000).await;
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, BUDGET / 2 + 250_000);
+
+    h.warp_to(START + DURATION * 3).await;
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, BUDGET);
+    let ix = h.keys.set_reward_rate(h.authority(), 0, 250);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::NoActiveCampaign);
+}
+
+#[tokio::test]
+async fn unallocated_rewards_can_be_reclaimed() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    // 999 more than a whole rate of 1_000 per second can emit
+    h.fund(0, BUDGET + 999, START, START + DURATION).await.unwrap();
+    let reward_mint = h.reward_mint;
+    let destination = pda::token_account(&h.authority(), &reward_mint, &spl_token::ID);
+
+    let stranger = Keypair::new();
+    let ix = h.keys.reclaim_rewards(stranger.pubkey(), destination, 0);
+    let result = h.process(&[ix], &[&stranger]).await;
+    assert_error(result, ErrorCode::ConstraintHasOne);
+
+    // Nobody is staked for the first half, so those emissions go unallocated
+    h.warp_to(START + DURATION / 2).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    let ix = h.keys.reclaim_rewards(h.authority(), destination, 0);
+    h.process(&[ix], &[]).await.unwrap();
+    assert_eq!(h.balance(&destination).await, BUDGET / 2 + 999);
+    let ix = h.keys.reclaim_rewards(h.authority(), destination, 0);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::NothingToReclaim);
+
+    // What alice earned stays in the vault for her
+    h.warp_to(START + DURATION).await;
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, BUDGET / 2);
+    let reward_vault = h.keys.reward_streams[0].1;
+    assert_eq!(h.balance(&reward_vault).await, 0);
+}
+
+#[tokio::test]
+async fn stakers_share_emissions_pro_rata() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(1_000).await;
+    h.stake(&alice, 250, 0).await.unwrap();
+    h.stake(&bob, 750, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+
+    h.warp_to(START + DURATION).await;
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    // Unstaking pays out pending rewards alongside the principal
+    let ix = h.keys.unstake_tokens(&bob.staker, 750);
+    h.process(&[ix], &[&bob.keypair]).await.unwrap();
+
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, 250_000);
+    assert_eq!(h.balance(&bob.staker.reward_accounts[0]).await, 750_000);
+    assert_eq!(h.balance(&bob.staker.token_account).await, 1_000);
+}
+
+#[tokio::test]
+async fn campaigns_are_validated_and_queued() {
+    let mut h = Harness::new().await;
+
+    let result = h.fund(0, BUDGET, START - 1, START + DURATION).await;
+    assert_error(result, StakingError::InvalidRewardWindow);
+    let result = h.fund(0, BUDGET, START + DURATION, START + DURATION).await;
+    assert_error(result, StakingError::InvalidRewardWindow);
+    let result = h.fund(0, 10, START, START + DURATION).await;
+    assert_error(result, StakingError::BudgetTooSmall);
+
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    let result = h.fund(0, BUDGET, START + 10, START + DURATION).await;
+    assert_error(result, StakingError::CampaignOverlap);
+    h.fund(0, BUDGET, START + DURATION, START + 2 * DURATION).await.unwrap();
+    let result = h.fund(0, BUDGET, START + 2 * DURATION, START + 3 * DURATION).await;
+    assert_error(result, StakingError::CampaignAlreadyQueued);
+
+    let stream = h.pool().await.reward_streams[0];
+    assert_eq!(stream.reward_budget, BUDGET);
+    assert_eq!(stream.queued_campaign.budget, BUDGET);
+    assert_eq!(h.balance(&stream.reward_vault).await, 2 * BUDGET);
+}
+
+#[tokio::test]
+async fn locked_stake_is_boosted_and_held() {
+    let mut h = Harness::with_config(false, locked_tiers(), 0, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 1).await.unwrap();
+
+    let position = h.user_stake(&alice).await;
+    assert_eq!(position.lock_end, START + WEEK);
+    assert_eq!(position.effective_amount, 1_500);
+    assert_eq!(h.pool().await.total_effective_stake, 1_500);
+
+    let ix = h.keys.unstake_tokens(&alice.staker, 1_000);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::StakeLocked);
+
+    h.warp_to(START + WEEK).await;
+    let ix = h.keys.unstake_tokens(&alice.staker, 1_000);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 1_000);
+    assert_eq!(h.pool().await.total_effective_stake, 0);
+}
+
+#[tokio::test]
+async fn top_ups_cannot_borrow_a_longer_lock() {
+    let mut h = Harness::with_config(false, locked_tiers(), 0, no_penalty()).await;
+    let alice = h.new_staker(1_100).await;
+    h.stake(&alice, 1, 1).await.unwrap();
+
+    // A day before the lock ends, an unlocked deposit would earn the boost
+    // on the whole position for that day
+    let now = START + WEEK - 86_400;
+    h.warp_to(now).await;
+    assert_error(h.stake(&alice, 999, 0).await, StakingError::LockTierTooShort);
+
+    // A full tier re-locks everything from now
+    h.stake(&alice, 999, 1).await.unwrap();
+    let position = h.user_stake(&alice).await;
+    assert_eq!(position.lock_start, now);
+    assert_eq!(position.lock_end, now + WEEK);
+    assert_eq!(position.effective_amount, 1_500);
+
+    // Once the lock is over, unlocked top-ups are fine again
+    h.warp_to(now + WEEK).await;
+    h.stake(&alice, 100, 0).await.unwrap();
+    let position = h.user_stake(&alice).await;
+    assert_eq!(position.multiplier_bps as u64, BPS_DENOMINATOR);
+    assert_eq!(position.effective_amount, 1_100);
+}
+
+#[tokio::test]
+async fn expired_locks_can_be_reset_by_anyone() {
+    let mut h = Harness::with_config(false, locked_tiers(), 0, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 1).await.unwrap();
+    h.stake(&bob, 1_000, 0).await.unwrap();
+    assert_eq!(h.pool().await.total_effective_stake, 2_500);
+
+    let alice_stake = h.keys.user_stake(&alice.staker);
+    let ix = h.keys.expire_lock(alice_stake);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::StakeLocked);
+
+    // Without the crank alice would keep her boosted share until she next acted
+    h.warp_to(START + WEEK).await;
+    let ix = h.keys.expire_lock(alice_stake);
+    h.process(&[ix], &[]).await.unwrap();
+    let position = h.user_stake(&alice).await;
+    assert_eq!(position.multiplier_bps as u64, BPS_DENOMINATOR);
+    assert_eq!(position.effective_amount, 1_000);
+    assert_eq!(h.pool().await.total_effective_stake, 2_000);
+
+    let ix = h.keys.expire_lock(alice_stake);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::LockNotBoosted);
+    let ix = h.keys.expire_lock(h.keys.user_stake(&bob.staker));
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::LockNotBoosted);
+}
+
+#[tokio::test]
+async fn early_exit_penalty_goes_to_treasury() {
+    let mut h = Harness::with_config(false, locked_tiers(), 0, no_penalty()).await;
+    let owner = Keypair::new().pubkey();
+    let token_mint = h.token_mint;
+    let treasury = h.create_token_account(&owner, &token_mint).await;
+    let update = PoolConfigUpdate {
+        early_exit_penalty: Some(PenaltyConfig {
+            curve: PenaltyCurve::Flat,
+            penalty_bps: 1_000,
+            destination: PenaltyDestination::Treasury,
+            treasury,
+        }),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+    h.refresh_keys().await;
+
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 1).await.unwrap();
+    let ix = h.keys.unstake_tokens(&alice.staker, 1_000);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+
+    assert_eq!(h.balance(&alice.staker.token_account).await, 900);
+    assert_eq!(h.balance(&treasury).await, 100);
+}
+
+#[tokio::test]
+async fn redistributed_penalty_is_burned_without_stakers() {
+    let penalty = PenaltyConfig {
+        curve: PenaltyCurve::Flat,
+        penalty_bps: 1_000,
+        destination: PenaltyDestination::Redistribute,
+        treasury: Pubkey::default(),
+    };
+    let mut h = Harness::with_config(true, locked_tiers(), 0, penalty).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 1).await.unwrap();
+
+    // Nobody is left to share the penalty, so it must not sit in the vault
+    let ix = h.keys.unstake_tokens(&alice.staker, 1_000);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 900);
+    let reward_vault = h.keys.reward_streams[0].1;
+    assert_eq!(h.balance(&reward_vault).await, 0);
+    let stake_vault = h.keys.stake_vault;
+    assert_eq!(h.balance(&stake_vault).await, 0);
+}
+
+#[tokio::test]
+async fn unbonding_queue_enforces_cooldown() {
+    let mut h = Harness::with_config(false, unlocked(), 86_400, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+
+    let ix = h.keys.unstake_tokens(&alice.staker, 1_000);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::UnbondingRequired);
+
+    let ix = h.keys.request_unstake(&alice.staker, 400);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let pool = h.pool().await;
+    assert_eq!(pool.total_staked, 600);
+    assert_eq!(pool.total_unbonding, 400);
+
+    let ix = h.keys.withdraw_unstaked(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::CooldownActive);
+
+    h.warp_to(START + 86_400).await;
+    let ix = h.keys.withdraw_unstaked(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 400);
+    assert_eq!(h.pool().await.total_unbonding, 0);
+
+    // A cancelled request goes straight back to earning
+    let ix = h.keys.request_unstake(&alice.staker, 100);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let ix = h.keys.cancel_unstake(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let position = h.user_stake(&alice).await;
+    assert_eq!(position.amount, 600);
+    assert_eq!(position.unbonding_amount, 0);
+
+    let ix = h.keys.withdraw_unstaked(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::NothingToWithdraw);
+}
+
+#[tokio::test]
+async fn authority_transfer_and_renounce() {
+    let mut h = Harness::new().await;
+    let successor = Keypair::new();
+    let stranger = Keypair::new();
+
+    let ix = h.keys.propose_authority(h.authority(), Pubkey::default());
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::InvalidAuthority);
+
+    let ix = h.keys.propose_authority(h.authority(), successor.pubkey());
+    h.process(&[ix], &[]).await.unwrap();
+    let ix = h.keys.accept_authority(stranger.pubkey());
+    let result = h.process(&[ix], &[&stranger]).await;
+    assert_error(result, StakingError::NotPendingAuthority);
+
+    let ix = h.keys.accept_authority(successor.pubkey());
+    h.process(&[ix], &[&successor]).await.unwrap();
+    let pool = h.pool().await;
+    assert_eq!(pool.authority, successor.pubkey());
+    assert_eq!(pool.pending_authority, Pubkey::default());
+
+    // The old authority is locked out, and so is everyone once renounced
+    let ix = h.keys.set_paused(h.authority(), true);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, ErrorCode::ConstraintHasOne);
+
+    let ix = h.keys.renounce_authority(successor.pubkey());
+    h.process(&[ix], &[&successor]).await.unwrap();
+    assert_eq!(h.pool().await.authority, Pubkey::default());
+    let ix = h.keys.set_paused(successor.pubkey(), true);
+    let result = h.process(&[ix], &[&successor]).await;
+    assert_error(result, ErrorCode::ConstraintHasOne);
+}
+
+#[tokio::test]
+async fn cancel_authority_transfer() {
+    let mut h = Harness::new().await;
+
+    let ix = h.keys.cancel_authority_transfer(h.authority());
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::NoPendingAuthority);
+
+    let successor = Keypair::new();
+    let ix = h.keys.propose_authority(h.authority(), successor.pubkey());
+    h.process(&[ix], &[]).await.unwrap();
+    let ix = h.keys.cancel_authority_transfer(h.authority());
+    h.process(&[ix], &[]).await.unwrap();
+
+    let ix = h.keys.accept_authority(successor.pubkey());
+    let result = h.process(&[ix], &[&successor]).await;
+    assert_error(result, StakingError::NotPendingAuthority);
+}
+
+#[tokio::test]
+async fn pause_blocks_stakes_and_claims_but_not_exits() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 500, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+
+    h.warp_to(START + DURATION / 2).await;
+    let ix = h.keys.set_paused(h.authority(), true);
+    h.process(&[ix], &[]).await.unwrap();
+
+    let result = h.stake(&alice, 100, 0).await;
+    assert_error(result, StakingError::PoolPaused);
+    let ix = h.keys.claim_rewards(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::PoolPaused);
+    // Unstaking nothing would otherwise be a claim
+    let ix = h.keys.unstake_tokens(&alice.staker, 0);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::InvalidAmount);
+
+    // Principal leaves, but rewards stay pending while paused
+    let ix = h.keys.unstake_tokens(&alice.staker, 200);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 700);
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, 0);
+    assert_eq!(h.user_stake(&alice).await.rewards[0].pending_rewards, BUDGET / 2);
+
+    let ix = h.keys.set_paused(h.authority(), false);
+    h.process(&[ix], &[]).await.unwrap();
+    h.stake(&alice, 100, 0).await.unwrap();
+    assert_eq!(h.user_stake(&alice).await.amount, 400);
+}
+
+#[tokio::test]
+async fn emergency_withdraw_returns_principal() {
+    let mut h = Harness::with_config(false, unlocked(), 86_400, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    let ix = h.keys.request_unstake(&alice.staker, 300);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+
+    let ix = h.keys.emergency_withdraw(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::NotEmergencyMode);
+
+    let ix = h.keys.enable_emergency_mode(h.authority());
+    h.process(&[ix], &[]).await.unwrap();
+    let ix = h.keys.set_paused(h.authority(), false);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::EmergencyMode);
+
+    // Staked and unbonding principal both come back; rewards are forfeited
+    h.warp_to(START + DURATION / 2).await;
+    let ix = h.keys.emergency_withdraw(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 1_000);
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, 0);
+    let pool = h.pool().await;
+    assert_eq!(pool.total_staked, 0);
+    assert_eq!(pool.total_unbonding, 0);
+}
+
+#[tokio::test]
+async fn emergency_withdraw_drops_voting_power() {
+    let tiers = vec![LockTier {
+        duration: 52 * WEEK,
+        multiplier_bps: BPS_DENOMINATOR as u16,
+    }];
+    let mut h = Harness::with_config(false, tiers, 0, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.stake(&bob, 1_000, 0).await.unwrap();
+
+    let ix = h.keys.enable_emergency_mode(h.authority());
+    h.process(&[ix], &[]).await.unwrap();
+    h.warp_to(START + WEEK).await;
+    let ix = h.keys.emergency_withdraw(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+
+    // Only bob's lock is left counting
+    let now = START + WEEK;
+    let alice_stake = h.keys.user_stake(&alice.staker);
+    let bob_stake = h.keys.user_stake(&bob.staker);
+    assert_eq!(h.simulate_u64(h.keys.get_voting_power(alice_stake, now)).await, 0);
+    let bob_power = h.simulate_u64(h.keys.get_voting_power(bob_stake, now)).await;
+    assert!(bob_power > 0);
+    assert_eq!(h.simulate_u64(h.keys.get_total_voting_power(now)).await, bob_power);
+}
+
+#[tokio::test]
+async fn stake_limits_are_enforced() {
+    let mut h = Harness::new().await;
+    let update = PoolConfigUpdate {
+        max_stake_per_user: Some(100),
+        min_stake_amount: Some(200),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::InvalidStakeLimits);
+
+    let update = PoolConfigUpdate {
+        max_total_staked: Some(1_500),
+        max_stake_per_user: Some(1_000),
+        min_stake_amount: Some(100),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+
+    let alice = h.new_staker(2_000).await;
+    let bob = h.new_staker(2_000).await;
+    assert_error(h.stake(&alice, 99, 0).await, StakingError::StakeBelowMinimum);
+    assert_error(h.stake(&alice, 1_001, 0).await, StakingError::UserStakeCapExceeded);
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    assert_error(h.stake(&bob, 600, 0).await, StakingError::PoolStakeCapExceeded);
+    h.stake(&bob, 500, 0).await.unwrap();
+}
+
+#[tokio::test]
+async fn stake_caps_bound_compounding() {
+    let mut h = Harness::with_config(true, unlocked(), 0, no_penalty()).await;
+    let update = PoolConfigUpdate {
+        max_total_staked: Some(1_500),
+        max_stake_per_user: Some(1_000),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(500).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.stake(&bob, 500, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+
+    // One second of emissions: 666 for alice and 333 for bob
+    h.warp_to(START + 1).await;
+    let ix = h.keys.compound(&alice.staker, None);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::UserStakeCapExceeded);
+    let ix = h.keys.compound(&bob.staker, None);
+    let result = h.process(&[ix], &[&bob.keypair]).await;
+    assert_error(result, StakingError::PoolStakeCapExceeded);
+}
+
+#[tokio::test]
+async fn cancelled_unstakes_respect_stake_caps() {
+    let mut h = Harness::with_config(false, unlocked(), 86_400, no_penalty()).await;
+    let update = PoolConfigUpdate {
+        max_total_staked: Some(1_500),
+        max_stake_per_user: Some(1_000),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+
+    // Restaking into the room an unstake request freed up
+    let alice = h.new_staker(1_400).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    let ix = h.keys.request_unstake(&alice.staker, 400);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    h.stake(&alice, 400, 0).await.unwrap();
+    let ix = h.keys.cancel_unstake(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::UserStakeCapExceeded);
+
+    let bob = h.new_staker(500).await;
+    let carol = h.new_staker(500).await;
+    h.stake(&bob, 500, 0).await.unwrap();
+    let ix = h.keys.request_unstake(&bob.staker, 500);
+    h.process(&[ix], &[&bob.keypair]).await.unwrap();
+    h.stake(&carol, 500, 0).await.unwrap();
+    let ix = h.keys.cancel_unstake(&bob.staker);
+    let result = h.process(&[ix], &[&bob.keypair]).await;
+    assert_error(result, StakingError::PoolStakeCapExceeded);
+    assert_eq!(h.pool().await.total_staked, 1_500);
+}
+
+#[tokio::test]
+async fn allowlist_gates_stakes() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(1_000).await;
+    let carol = h.new_staker(1_000).await;
+
+    let alice_leaf = allowlist_leaf(&alice.staker.authority, 500);
+    let bob_leaf = allowlist_leaf(&bob.staker.authority, 0);
+    let root = allowlist_node(alice_leaf, bob_leaf);
+    let ix = h.keys.set_allowlist_root(h.authority(), root);
+    h.process(&[ix], &[]).await.unwrap();
+
+    let alice_proof = AllowlistProof {
+        cap: 500,
+        proof: vec![bob_leaf],
+    };
+    let bob_proof = AllowlistProof {
+        cap: 0,
+        proof: vec![alice_leaf],
+    };
+
+    assert_error(h.stake(&alice, 100, 0).await, StakingError::NotAllowlisted);
+    let ix = h.keys.stake_tokens(&alice.staker, 600, 0, Some(alice_proof.clone()));
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::AllowlistCapExceeded);
+    let ix = h.keys.stake_tokens(&alice.staker, 500, 0, Some(alice_proof.clone()));
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+
+    // A zero cap leaves only the pool limits
+    let ix = h.keys.stake_tokens(&bob.staker, 1_000, 0, Some(bob_proof));
+    h.process(&[ix], &[&bob.keypair]).await.unwrap();
+
+    // Proofs are bound to the wallet they were issued for
+    let ix = h.keys.stake_tokens(&carol.staker, 100, 0, Some(alice_proof));
+    let result = h.process(&[ix], &[&carol.keypair]).await;
+    assert_error(result, StakingError::NotAllowlisted);
+
+    let ix = h.keys.set_allowlist_root(h.authority(), [0; 32]);
+    h.process(&[ix], &[]).await.unwrap();
+    h.stake(&carol, 100, 0).await.unwrap();
+}
+
+#[tokio::test]
+async fn allowlist_caps_compounding() {
+    let mut h = Harness::with_config(true, unlocked(), 0, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(0).await;
+
+    let alice_leaf = allowlist_leaf(&alice.staker.authority, 0);
+    let bob_leaf = allowlist_leaf(&bob.staker.authority, 300);
+    let root = allowlist_node(alice_leaf, bob_leaf);
+    let ix = h.keys.set_allowlist_root(h.authority(), root);
+    h.process(&[ix], &[]).await.unwrap();
+    let alice_proof = AllowlistProof {
+        cap: 0,
+        proof: vec![bob_leaf],
+    };
+
+    let ix = h.keys.stake_tokens(&alice.staker, 1_000, 0, Some(alice_proof.clone()));
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+
+    // Compounding adds stake to the wallet, so it needs the owner's proof too
+    h.warp_to(START + 1).await;
+    let ix = h.keys.compound(&alice.staker, None);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::NotAllowlisted);
+    let ix = h.keys.compound(&alice.staker, Some(alice_proof));
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+}
+
+#[tokio::test]
+async fn allowlist_caps_count_unbonding_stake() {
+    let mut h = Harness::with_config(false, unlocked(), 86_400, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(0).await;
+
+    let alice_leaf = allowlist_leaf(&alice.staker.authority, 500);
+    let bob_leaf = allowlist_leaf(&bob.staker.authority, 0);
+    let ix = h.keys.set_allowlist_root(h.authority(), allowlist_node(alice_leaf, bob_leaf));
+    h.process(&[ix], &[]).await.unwrap();
+    let alice_proof = AllowlistProof {
+        cap: 500,
+        proof: vec![bob_leaf],
+    };
+
+    // A cancel could put the unbonding stake back on top of a fresh deposit
+    let ix = h.keys.stake_tokens(&alice.staker, 500, 0, Some(alice_proof.clone()));
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let ix = h.keys.request_unstake(&alice.staker, 300);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let ix = h.keys.stake_tokens(&alice.staker, 1, 0, Some(alice_proof.clone()));
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::AllowlistCapExceeded);
+
+    let ix = h.keys.cancel_unstake(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.user_stake(&alice).await.amount, 500);
+}
+
+#[tokio::test]
+async fn pool_config_updates_are_validated() {
+    let mut h = Harness::new().await;
+    let reward_mint = h.reward_mint;
+    let wrong_mint_treasury = h.create_token_account(&Keypair::new().pubkey(), &reward_mint).await;
+    let rejected = [
+        (
+            PoolConfigUpdate {
+                unbonding_period: Some(MAX_UNBONDING_PERIOD + 1),
+                ..Default::default()
+            },
+            StakingError::InvalidUnbondingPeriod,
+        ),
+        (
+            PoolConfigUpdate {
+                lock_tiers: Some(vec![]),
+                ..Default::default()
+            },
+            StakingError::InvalidLockTierConfig,
+        ),
+        (
+            PoolConfigUpdate {
+                lock_tiers: Some(vec![LockTier {
+                    duration: 0,
+                    multiplier_bps: 20_000,
+                }]),
+                ..Default::default()
+            },
+            StakingError::InvalidLockTierConfig,
+        ),
+        (
+            PoolConfigUpdate {
+                early_exit_penalty: Some(PenaltyConfig {
+                    penalty_bps: BPS_DENOMINATOR as u16 + 1,
+                    ..no_penalty()
+                }),
+                ..Default::default()
+            },
+            StakingError::InvalidPenaltyConfig,
+        ),
+        (
+            // Stream 0 pays out a different mint, so there is nowhere to redistribute to
+            PoolConfigUpdate {
+                early_exit_penalty: Some(PenaltyConfig {
+                    destination: PenaltyDestination::Redistribute,
+                    ..no_penalty()
+                }),
+                ..Default::default()
+            },
+            StakingError::InvalidPenaltyConfig,
+        ),
+        (
+            // Every penalised exit would fail to pay a treasury of another mint
+            PoolConfigUpdate {
+                early_exit_penalty: Some(PenaltyConfig {
+                    destination: PenaltyDestination::Treasury,
+                    treasury: wrong_mint_treasury,
+                    ..no_penalty()
+                }),
+                ..Default::default()
+            },
+            StakingError::InvalidPenaltyConfig,
+        ),
+    ];
+    for (update, error) in rejected {
+        let ix = h.keys.update_pool_config(h.authority(), update);
+        let result = h.process(&[ix], &[]).await;
+        assert_error(result, error);
+    }
+
+    let update = PoolConfigUpdate {
+        lock_tiers: Some(locked_tiers()),
+        unbonding_period: Some(3_600),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+    let pool = h.pool().await;
+    assert_eq!(pool.lock_tier_count, 2);
+    assert_eq!(pool.unbonding_period, 3_600);
+
+    let stranger = Keypair::new();
+    let ix = h.keys.update_pool_config(stranger.pubkey(), PoolConfigUpdate::default());
+    let result = h.process(&[ix], &[&stranger]).await;
+    assert_error(result, ErrorCode::ConstraintHasOne);
+}
+
+#[tokio::test]
+async fn extra_reward_streams_pay_through_remaining_accounts() {
+    let mut h = Harness::new().await;
+    let bonus_mint = h.create_mint().await;
+    let ix = h.keys.add_reward_stream(h.authority(), bonus_mint);
+    h.process(&[ix], &[]).await.unwrap();
+    h.refresh_keys().await;
+    assert_eq!(h.keys.reward_streams.len(), 2);
+
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    h.fund(1, 2 * BUDGET, START, START + DURATION).await.unwrap();
+    h.warp_to(START + DURATION).await;
+
+    let mut ix = h.keys.claim_rewards(&alice.staker);
+    ix.accounts.truncate(ix.accounts.len() - 3);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::MissingRewardAccounts);
+
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, BUDGET);
+    assert_eq!(h.balance(&alice.staker.reward_accounts[1]).await, 2 * BUDGET);
+}
+
+#[tokio::test]
+async fn reward_fee_is_capped_and_paid_on_claims() {
+    let mut h = Harness::new().await;
+    let recipient = Keypair::new().pubkey();
+    let rejected = [
+        (MAX_REWARD_FEE_BPS + 1, Some(recipient)),
+        // A fee needs somewhere to go
+        (1_000, None),
+    ];
+    for (reward_fee_bps, fee_recipient) in rejected {
+        let update = PoolConfigUpdate {
+            reward_fee_bps: Some(reward_fee_bps),
+            fee_recipient,
+            ..Default::default()
+        };
+        let ix = h.keys.update_pool_config(h.authority(), update);
+        let result = h.process(&[ix], &[]).await;
+        assert_error(result, StakingError::InvalidRewardFee);
+    }
+
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    let update = PoolConfigUpdate {
+        reward_fee_bps: Some(1_000),
+        fee_recipient: Some(recipient),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+    let reward_mint = h.reward_mint;
+    let fee_account = h.create_token_account(&recipient, &reward_mint).await;
+    h.warp_to(START + DURATION).await;
+
+    // Keys fetched before the fee was set leave the fee account out
+    let ix = h.keys.claim_rewards(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::MissingFeeAccount);
+
+    h.refresh_keys().await;
+    let mut keys = h.keys.clone();
+    keys.fee_recipient = Some(alice.staker.authority);
+    let ix = keys.claim_rewards(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::InvalidFeeAccount);
+
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, BUDGET * 9 / 10);
+    assert_eq!(h.balance(&fee_account).await, BUDGET / 10);
+    assert_eq!(h.pool().await.reward_streams[0].fees_collected, BUDGET / 10);
+}
+
+#[tokio::test]
+async fn reward_fee_rounds_down_on_small_payouts() {
+    let mut h = Harness::new().await;
+    let recipient = Keypair::new().pubkey();
+    let update = PoolConfigUpdate {
+        reward_fee_bps: Some(1),
+        fee_recipient: Some(recipient),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+    h.refresh_keys().await;
+    let reward_mint = h.reward_mint;
+    let fee_account = h.create_token_account(&recipient, &reward_mint).await;
+
+    // Below 10_000 a 1 bps fee is less than one token
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, 9_000, START, START + DURATION).await.unwrap();
+    h.warp_to(START + DURATION).await;
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, 9_000);
+    assert_eq!(h.balance(&fee_account).await, 0);
+    assert_eq!(h.pool().await.reward_streams[0].fees_collected, 0);
+}
+
+#[tokio::test]
+async fn reward_fee_changes_apply_to_unclaimed_rewards() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    h.warp_to(START + DURATION / 2).await;
+
+    // Half the budget accrued fee-free, but the fee is taken on payment
+    let recipient = Keypair::new().pubkey();
+    let update = PoolConfigUpdate {
+        reward_fee_bps: Some(1_000),
+        fee_recipient: Some(recipient),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+    h.refresh_keys().await;
+    let reward_mint = h.reward_mint;
+    let fee_account = h.create_token_account(&recipient, &reward_mint).await;
+
+    // Quoted pending rewards are what a claim would pay
+    let pool = h.pool().await;
+    let position = h.user_stake(&alice).await;
+    let pending = rewards::pending_rewards(&pool, &position, START + DURATION / 2).unwrap();
+    assert_eq!(pending, vec![BUDGET / 2 * 9 / 10]);
+
+    let ix = h.keys.claim_rewards(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.reward_accounts[0]).await, BUDGET / 2 * 9 / 10);
+    assert_eq!(h.balance(&fee_account).await, BUDGET / 20);
+}
+
+#[tokio::test]
+async fn compound_restakes_rewards() {
+    let mut h = Harness::with_config(true, unlocked(), 0, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+
+    h.warp_to(START + DURATION / 2).await;
+    let ix = h.keys.compound(&alice.staker, None);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let position = h.user_stake(&alice).await;
+    assert_eq!(position.amount, 1_000 + BUDGET / 2);
+    assert_eq!(position.rewards[0].pending_rewards, 0);
+    assert_eq!(h.pool().await.total_staked, 1_000 + BUDGET / 2);
+    let stake_vault = h.keys.stake_vault;
+    assert_eq!(h.balance(&stake_vault).await, 1_000 + BUDGET / 2);
+
+    // Anyone may crank positions that opted in
+    let alice_stake = h.keys.user_stake(&alice.staker);
+    h.warp_to(START + DURATION).await;
+    let ix = h.keys.compound_for(alice_stake, None);
+    let result = h.process(&[ix], &[]).await;
+    assert_error(result, StakingError::AutoCompoundDisabled);
+
+    let ix = h.keys.set_auto_compound(&alice.staker, true);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let ix = h.keys.compound_for(alice_stake, None);
+    h.process(&[ix], &[]).await.unwrap();
+    // The second half is shared over a stake that no longer divides evenly,
+    // and rounding favours the pool
+    let amount = h.user_stake(&alice).await.amount;
+    assert!((1_000 + BUDGET - 1..=1_000 + BUDGET).contains(&amount));
+}
+
+#[tokio::test]
+async fn compound_needs_stake_mint_rewards() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+
+    let ix = h.keys.compound(&alice.staker, None);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::CompoundUnsupported);
+}
+
+#[tokio::test]
+async fn stake_vault_is_kept_apart_from_rewards() {
+    let mut h = Harness::with_config(true, unlocked(), 0, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    assert_eq!(h.keys.stake_vault, pda::stake_vault(&h.keys.stake_pool));
+
+    // The reward vault holds the same mint and is pool-owned, but it is not
+    // the stake vault
+    let mut keys = h.keys.clone();
+    keys.stake_vault = keys.reward_streams[0].1;
+    let ix = keys.unstake_tokens(&alice.staker, 1_000);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, ErrorCode::ConstraintAddress);
+
+    h.warp_to(START + DURATION / 2).await;
+    let ix = keys.compound(&alice.staker, None);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, ErrorCode::ConstraintAddress);
+}
+
+#[tokio::test]
+async fn voting_power_follows_locks() {
+    let tiers = vec![LockTier {
+        duration: 52 * WEEK,
+        multiplier_bps: BPS_DENOMINATOR as u16,
+    }];
+    let mut h = Harness::with_config(false, tiers, 0, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+
+    let alice_stake = h.keys.user_stake(&alice.staker);
+    let user_power = h.simulate_u64(h.keys.get_voting_power(alice_stake, START)).await;
+    let total_power = h.simulate_u64(h.keys.get_total_voting_power(START)).await;
+    assert!(user_power > 0);
+    assert_eq!(user_power, total_power);
+
+    // Power decays to nothing once the lock has run out
+    let later = START + 53 * WEEK;
+    assert_eq!(h.simulate_u64(h.keys.get_voting_power(alice_stake, later)).await, 0);
+    assert_eq!(h.simulate_u64(h.keys.get_total_voting_power(later)).await, 0);
+
+    // A timestamp too far back is an error, not a panic
+    let ix = h.keys.get_voting_power(alice_stake, i64::MIN);
+    assert_error(h.process(&[ix], &[]).await, StakingError::MathOverflow);
+}
+
+const GOVERNANCE_PROGRAM: Pubkey =
+    solana_sdk::pubkey!("GovER5Lthms3bLBqWub97yVrMmEogzX7xNjdXpPPCVZw");
+const VOTING_PERIOD: i64 = 3 * 86_400;
+
+// Writes a RealmV2 account, as SPL Governance lays it out, governed by
+// `community_mint` and controlled by `authority`
+fn create_realm(h: &mut Harness, authority: &Pubkey, community_mint: Pubkey) -> Pubkey {
+    let header = RealmHeader {
+        account_type: REALM_V2_ACCOUNT_TYPE,
+        community_mint,
+        config: RealmConfigHeader {
+            legacy: [0; 2],
+            reserved: [0; 6],
+            min_community_weight_to_create_governance: 1,
+            community_mint_max_voter_weight_source: (0, 10_000_000_000),
+            council_mint: None,
+        },
+        reserved: [0; 6],
+        legacy: 0,
+        authority: Some(*authority),
+    };
+    let mut data = header.try_to_vec().unwrap();
+    "Staking DAO".to_string().serialize(&mut data).unwrap();
+    data.extend([0; 128]);
+    let realm = Pubkey::new_unique();
+    let account = Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: GOVERNANCE_PROGRAM,
+        executable: false,
+        rent_epoch: 0,
+    };
+    h.context.set_account(&realm, &account.into());
+    realm
+}
+
+#[tokio::test]
+async fn registrar_needs_the_realm_authority_and_mint() {
+    let mut h = Harness::with_config(false, unlocked(), VOTING_PERIOD, no_penalty()).await;
+    let realm_authority = Keypair::new();
+    let token_mint = h.token_mint;
+    let realm = create_realm(&mut h, &realm_authority.pubkey(), token_mint);
+    let authority = h.authority();
+
+    // The pool authority alone cannot claim a realm
+    let stranger = Keypair::new();
+    let ix = h.keys.create_registrar(authority, stranger.pubkey(), realm, GOVERNANCE_PROGRAM, 1);
+    let result = h.process(&[ix], &[&stranger]).await;
+    assert_error(result, StakingError::InvalidRealmAuthority);
+    let fake_program = Pubkey::new_unique();
+    let ix = h.keys.create_registrar(authority, realm_authority.pubkey(), realm, fake_program, 1);
+    let result = h.process(&[ix], &[&realm_authority]).await;
+    assert_error(result, StakingError::InvalidRealm);
+    let other_realm = create_realm(&mut h, &realm_authority.pubkey(), Pubkey::new_unique());
+    let ix = h.keys.create_registrar(
+        authority,
+        realm_authority.pubkey(),
+        other_realm,
+        GOVERNANCE_PROGRAM,
+        1,
+    );
+    let result = h.process(&[ix], &[&realm_authority]).await;
+    assert_error(result, StakingError::InvalidGoverningMint);
+
+    // Stake must take longer to leave than a vote takes to close
+    let ix = h.keys.create_registrar(
+        authority,
+        realm_authority.pubkey(),
+        realm,
+        GOVERNANCE_PROGRAM,
+        VOTING_PERIOD + 1,
+    );
+    let result = h.process(&[ix], &[&realm_authority]).await;
+    assert_error(result, StakingError::UnbondingShorterThanVoting);
+    let ix = h.keys.create_registrar(
+        authority,
+        realm_authority.pubkey(),
+        realm,
+        GOVERNANCE_PROGRAM,
+        VOTING_PERIOD,
+    );
+    h.process(&[ix], &[&realm_authority]).await.unwrap();
+
+    let registrar: Registrar = h.account(&pda::registrar(&realm, &h.token_mint)).await;
+    assert_eq!(registrar.stake_pool, h.keys.stake_pool);
+    assert_eq!(registrar.governance_program, GOVERNANCE_PROGRAM);
+    let pool = h.pool().await;
+    assert_eq!(pool.realm, realm);
+    assert_eq!(pool.realm_governing_token_mint, h.token_mint);
+    assert_eq!(pool.realm_voting_period, VOTING_PERIOD);
+
+    let update = PoolConfigUpdate {
+        unbonding_period: Some(VOTING_PERIOD - 1),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(authority, update);
+    assert_error(h.process(&[ix], &[]).await, StakingError::UnbondingShorterThanVoting);
+    let ix = h.keys.create_registrar(
+        authority,
+        realm_authority.pubkey(),
+        other_realm,
+        GOVERNANCE_PROGRAM,
+        VOTING_PERIOD,
+    );
+    let result = h.process(&[ix], &[&realm_authority]).await;
+    assert_error(result, StakingError::RealmAlreadySet);
+}
+
+#[tokio::test]
+async fn voter_weight_record_tracks_stake() {
+    let mut h = Harness::with_config(false, unlocked(), VOTING_PERIOD, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 700, 0).await.unwrap();
+
+    let ix = h.keys.update_voter_weight_record(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::GovernanceNotConfigured);
+
+    let realm_authority = Keypair::new();
+    let token_mint = h.token_mint;
+    let realm = create_realm(&mut h, &realm_authority.pubkey(), token_mint);
+    let ix = h.keys.create_registrar(
+        h.authority(),
+        realm_authority.pubkey(),
+        realm,
+        GOVERNANCE_PROGRAM,
+        VOTING_PERIOD,
+    );
+    h.process(&[ix], &[&realm_authority]).await.unwrap();
+    let ix = h.keys.update_voter_weight_record(&alice.staker);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+
+    let alice_stake = h.keys.user_stake(&alice.staker);
+    let record: VoterWeightRecord = h.account(&pda::voter_weight_record(&alice_stake)).await;
+    assert_eq!(record.realm, realm);
+    assert_eq!(record.governing_token_mint, h.token_mint);
+    assert_eq!(record.governing_token_owner, alice.staker.authority);
+    assert_eq!(record.voter_weight, 700);
+
+    // Emergency withdrawals skip the cooldown, so votes stop with them
+    let ix = h.keys.enable_emergency_mode(h.authority());
+    h.process(&[ix], &[]).await.unwrap();
+    let ix = h.keys.update_voter_weight_record(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::EmergencyMode);
+}
+
+#[tokio::test]
+async fn transferable_positions_get_no_voter_weight() {
+    let mut h = Harness::with_config(false, unlocked(), VOTING_PERIOD, no_penalty()).await;
+    let realm_authority = Keypair::new();
+    let token_mint = h.token_mint;
+    let realm = create_realm(&mut h, &realm_authority.pubkey(), token_mint);
+    let ix = h.keys.create_registrar(
+        h.authority(),
+        realm_authority.pubkey(),
+        realm,
+        GOVERNANCE_PROGRAM,
+        VOTING_PERIOD,
+    );
+    h.process(&[ix], &[&realm_authority]).await.unwrap();
+    enable_position_nfts(&mut h).await;
+
+    // The NFT could be sent on after voting and vote again
+    let mut alice = h.new_staker(1_000).await;
+    alice.staker.position = Some(open_position(&mut h, &alice, 1_000, None).await.unwrap());
+    let ix = h.keys.update_voter_weight_record(&alice.staker);
+    let result = h.process(&[ix], &[&alice.keypair]).await;
+    assert_error(result, StakingError::TransferablePositions);
+
+    // Receipts the same
+    let mut h = liquid_pool().await;
+    let token_mint = h.token_mint;
+    let realm = create_realm(&mut h, &realm_authority.pubkey(), token_mint);
+    let ix = h.keys.create_registrar(
+        h.authority(),
+        realm_authority.pubkey(),
+        realm,
+        GOVERNANCE_PROGRAM,
+        VOTING_PERIOD,
+    );
+    let result = h.process(&[ix], &[&realm_authority]).await;
+    assert_error(result, StakingError::TransferablePositions);
+}
+
+async fn liquid_pool() -> Harness {
+    let mut h = Harness::with_config(true, unlocked(), 0, no_penalty()).await;
+    let ix = h.keys.enable_receipt_mint(h.authority());
+    h.process(&[ix], &[]).await.unwrap();
+    h.refresh_keys().await;
+    h
+}
+
+async fn stake_liquid(
+    h: &mut Harness,
+    who: &TestStaker,
+    amount: u64,
+) -> std::result::Result<(), BanksClientError> {
+    let ix = h.keys.stake_liquid(&who.staker, amount, None);
+    h.process(&[ix], &[&who.keypair]).await
+}
+
+async fn unstake_liquid(
+    h: &mut Harness,
+    who: &TestStaker,
+    receipts: u64,
+) -> std::result::Result<(), BanksClientError> {
+    let ix = h.keys.unstake_liquid(&who.staker, receipts);
+    h.process(&[ix], &[&who.keypair]).await
+}
+
+#[tokio::test]
+async fn liquid_receipts_are_transferable_shares() {
+    let mut h = liquid_pool().await;
+    let receipt_mint = h.keys.receipt_mint.unwrap();
+    assert_eq!(receipt_mint, pda::receipt_mint(&h.keys.stake_pool));
+
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(0).await;
+    let carol = h.new_staker(1_000).await;
+    assert_error(h.stake(&alice, 1_000, 0).await, StakingError::LiquidStakesOnly);
+    stake_liquid(&mut h, &alice, 1_000).await.unwrap();
+    let alice_receipts = alice.staker.receipt_account.unwrap();
+    let bob_receipts = bob.staker.receipt_account.unwrap();
+    let carol_receipts = carol.staker.receipt_account.unwrap();
+    assert_eq!(h.balance(&alice_receipts).await, 1_000);
+
+    // Receipts are ordinary tokens
+    let ix = spl_token::instruction::transfer(
+        &spl_token::ID,
+        &alice_receipts,
+        &bob_receipts,
+        &alice.staker.authority,
+        &[],
+        400,
+    )
+    .unwrap();
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&bob_receipts).await, 400);
+
+    // One second of emissions doubles what each receipt redeems for
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    h.warp_to(START + 1).await;
+    let pool = h.pool().await;
+    let value = rewards::receipt_value(&pool, 1_000, 600, START + 1).unwrap();
+    assert_eq!(value, 1_200);
+
+    // A new stake buys in at the harvested rate
+    stake_liquid(&mut h, &carol, 1_000).await.unwrap();
+    assert_eq!(h.balance(&carol_receipts).await, 500);
+    let pool = h.pool().await;
+    assert_eq!(pool.total_staked, 3_000);
+    assert_eq!(pool.total_effective_stake, 3_000);
+
+    unstake_liquid(&mut h, &bob, 400).await.unwrap();
+    assert_eq!(h.balance(&bob.staker.token_account).await, 800);
+    assert_eq!(h.balance(&bob_receipts).await, 0);
+    unstake_liquid(&mut h, &alice, 600).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 1_200);
+    unstake_liquid(&mut h, &carol, 500).await.unwrap();
+    assert_eq!(h.balance(&carol.staker.token_account).await, 1_000);
+    assert_eq!(h.pool().await.total_staked, 0);
+    let stake_vault = h.keys.stake_vault;
+    assert_eq!(h.balance(&stake_vault).await, 0);
+}
+
+#[tokio::test]
+async fn liquid_rewards_wait_out_a_pause() {
+    let mut h = liquid_pool().await;
+    let alice = h.new_staker(1_000).await;
+    stake_liquid(&mut h, &alice, 1_000).await.unwrap();
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    h.warp_to(START + 1).await;
+
+    // Exits still work, at the rate from before the pause
+    let ix = h.keys.set_paused(h.authority(), true);
+    h.process(&[ix], &[]).await.unwrap();
+    assert_error(stake_liquid(&mut h, &alice, 1).await, StakingError::PoolPaused);
+    unstake_liquid(&mut h, &alice, 500).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 500);
+    assert_eq!(h.pool().await.liquid_deferred_rewards, 1_000);
+
+    let ix = h.keys.set_paused(h.authority(), false);
+    h.process(&[ix], &[]).await.unwrap();
+    unstake_liquid(&mut h, &alice, 500).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 2_000);
+    assert_eq!(h.pool().await.liquid_deferred_rewards, 0);
+}
+
+#[tokio::test]
+async fn liquid_deposits_count_against_wallet_caps() {
+    let mut h = liquid_pool().await;
+    let update = PoolConfigUpdate {
+        max_stake_per_user: Some(1_000),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+
+    // Sending receipts away does not make room under the cap
+    let alice = h.new_staker(2_000).await;
+    let bob = h.new_staker(0).await;
+    stake_liquid(&mut h, &alice, 600).await.unwrap();
+    let ix = spl_token::instruction::transfer(
+        &spl_token::ID,
+        &alice.staker.receipt_account.unwrap(),
+        &bob.staker.receipt_account.unwrap(),
+        &alice.staker.authority,
+        &[],
+        600,
+    )
+    .unwrap();
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let result = stake_liquid(&mut h, &alice, 401).await;
+    assert_error(result, StakingError::UserStakeCapExceeded);
+    stake_liquid(&mut h, &alice, 400).await.unwrap();
+}
+
+#[tokio::test]
+async fn receipt_mint_needs_an_empty_plain_pool() {
+    let mut h = Harness::with_config(true, unlocked(), 0, no_penalty()).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 100, 0).await.unwrap();
+    let ix = h.keys.enable_receipt_mint(h.authority());
+    assert_error(h.process(&[ix], &[]).await, StakingError::PoolNotEmpty);
+
+    // Receipts carry no lock or cooldown
+    let mut h = Harness::with_config(true, unlocked(), 100, no_penalty()).await;
+    let ix = h.keys.enable_receipt_mint(h.authority());
+    assert_error(h.process(&[ix], &[]).await, StakingError::LiquidPoolLocks);
+    let mut h = liquid_pool().await;
+    let update = PoolConfigUpdate {
+        unbonding_period: Some(100),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    assert_error(h.process(&[ix], &[]).await, StakingError::LiquidPoolLocks);
+
+    // Only rewards in the staking mint can be restaked into the rate
+    let bonus_mint = h.create_mint().await;
+    let ix = h.keys.add_reward_stream(h.authority(), bonus_mint);
+    assert_error(h.process(&[ix], &[]).await, StakingError::LiquidPoolRewards);
+    let mut h = Harness::new().await;
+    let ix = h.keys.enable_receipt_mint(h.authority());
+    assert_error(h.process(&[ix], &[]).await, StakingError::LiquidPoolRewards);
+}
+
+async fn enable_position_nfts(h: &mut Harness) {
+    let ix = h.keys.enable_position_nfts(
+        h.authority(),
+        "Staked".to_string(),
+        "STK".to_string(),
+        "https://example.com/position".to_string(),
+    );
+    h.process(&[ix], &[]).await.unwrap();
+}
+
+async fn open_position(
+    h: &mut Harness,
+    who: &TestStaker,
+    amount: u64,
+    allowlist_proof: Option<AllowlistProof>,
+) -> std::result::Result<Position, BanksClientError> {
+    let mint = Keypair::new();
+    let ix = h.keys.open_position(&who.staker, mint.pubkey(), amount, 0, allowlist_proof);
+    h.process(&[ix], &[&who.keypair, &mint]).await?;
+    Ok(Position {
+        mint: mint.pubkey(),
+        token_account: pda::token_account(&who.staker.authority, &mint.pubkey(), &h.token_program),
+    })
+}
+
+#[tokio::test]
+async fn position_nfts_need_an_empty_pool_and_short_metadata() {
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 100, 0).await.unwrap();
+    let ix = h.keys.enable_position_nfts(
+        h.authority(),
+        "Staked".to_string(),
+        "STK".to_string(),
+        String::new(),
+    );
+    assert_error(h.process(&[ix], &[]).await, StakingError::PoolNotEmpty);
+
+    let mut h = Harness::new().await;
+    let long = |len: usize| "x".repeat(len);
+    for (name, symbol, uri) in [
+        (long(POSITION_NAME_MAX_LEN + 1), long(1), long(1)),
+        (long(1), long(POSITION_SYMBOL_MAX_LEN + 1), long(1)),
+        (long(1), long(1), long(POSITION_URI_BASE_MAX_LEN + 1)),
+    ] {
+        let ix = h.keys.enable_position_nfts(h.authority(), name, symbol, uri);
+        assert_error(h.process(&[ix], &[]).await, StakingError::PositionMetadataTooLong);
+    }
+
+    // Receipts and NFTs would be two claims on the same stake
+    let mut h = liquid_pool().await;
+    let ix = h.keys.enable_position_nfts(
+        h.authority(),
+        "Staked".to_string(),
+        "STK".to_string(),
+        String::new(),
+    );
+    assert_error(h.process(&[ix], &[]).await, StakingError::PositionModeConflict);
+}
+
+#[tokio::test]
+async fn position_nfts_hold_their_stake() {
+    let mut h = Harness::new().await;
+    enable_position_nfts(&mut h).await;
+    let pool = h.pool().await;
+    assert!(pool.position_nfts);
+    let config: PositionNftConfig = h.account(&pda::position_config(&h.keys.stake_pool)).await;
+    assert_eq!(config.stake_pool, h.keys.stake_pool);
+    assert_eq!(config.name, "Staked");
+    assert_eq!(config.symbol, "STK");
+    assert_eq!(config.uri, "https://example.com/position");
+
+    // Wallet-keyed positions would bypass the NFT
+    let mut alice = h.new_staker(1_000).await;
+    assert_error(h.stake(&alice, 100, 0).await, StakingError::NftPositionsOnly);
+
+    let position = open_position(&mut h, &alice, 400, None).await.unwrap();
+    assert_eq!(h.balance(&position.token_account).await, 1);
+    let stake_vault = h.keys.stake_vault;
+    assert_eq!(h.balance(&stake_vault).await, 400);
+    let user_stake: UserStake =
+        h.account(&pda::user_stake(&position.mint, &h.keys.stake_pool)).await;
+    assert_eq!(user_stake.owner, position.mint);
+    assert!(user_stake.nft_position);
+    assert_eq!(user_stake.amount, 400);
+    assert_eq!(h.pool().await.total_staked, 400);
+
+    // Whoever holds the NFT controls the position
+    alice.staker.position = Some(position);
+    let ix = h.keys.unstake_tokens(&alice.staker, 400);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    assert_eq!(h.balance(&alice.staker.token_account).await, 1_000);
+    assert_eq!(h.pool().await.total_staked, 0);
+}
+
+#[tokio::test]
+async fn open_position_checks_run_before_minting() {
+    // Without the config there is nothing to describe the NFT with
+    let mut h = Harness::new().await;
+    let alice = h.new_staker(1_000).await;
+    let result = open_position(&mut h, &alice, 100, None).await.map(|_| ());
+    assert_error(result, ErrorCode::AccountNotInitialized);
+
+    let mut h = Harness::new().await;
+    enable_position_nfts(&mut h).await;
+    let alice = h.new_staker(1_000).await;
+    let bob = h.new_staker(1_000).await;
+    let result = open_position(&mut h, &alice, 0, None).await.map(|_| ());
+    assert_error(result, StakingError::InvalidAmount);
+
+    let ix = h.keys.set_paused(h.authority(), true);
+    h.process(&[ix], &[]).await.unwrap();
+    let result = open_position(&mut h, &alice, 100, None).await.map(|_| ());
+    assert_error(result, StakingError::PoolPaused);
+    let ix = h.keys.set_paused(h.authority(), false);
+    h.process(&[ix], &[]).await.unwrap();
+
+    // The cap bounds everything a wallet opens, across positions
+    let alice_leaf = allowlist_leaf(&alice.staker.authority, 500);
+    let bob_leaf = allowlist_leaf(&bob.staker.authority, 0);
+    let ix = h.keys.set_allowlist_root(h.authority(), allowlist_node(alice_leaf, bob_leaf));
+    h.process(&[ix], &[]).await.unwrap();
+    let alice_proof = AllowlistProof {
+        cap: 500,
+        proof: vec![bob_leaf],
+    };
+    let result = open_position(&mut h, &alice, 100, None).await.map(|_| ());
+    assert_error(result, StakingError::NotAllowlisted);
+    open_position(&mut h, &alice, 300, Some(alice_proof.clone())).await.unwrap();
+    let result = open_position(&mut h, &alice, 300, Some(alice_proof.clone())).await.map(|_| ());
+    assert_error(result, StakingError::AllowlistCapExceeded);
+    open_position(&mut h, &alice, 200, Some(alice_proof)).await.unwrap();
+    let wallet_stake: WalletStake =
+        h.account(&pda::wallet_stake(&alice.staker.authority, &h.keys.stake_pool)).await;
+    assert_eq!(wallet_stake.opened, 500);
+}
+
+#[tokio::test]
+async fn user_cap_bounds_every_position_a_wallet_opens() {
+    let mut h = Harness::new().await;
+    enable_position_nfts(&mut h).await;
+    let update = PoolConfigUpdate {
+        max_stake_per_user: Some(500),
+        ..Default::default()
+    };
+    let ix = h.keys.update_pool_config(h.authority(), update);
+    h.process(&[ix], &[]).await.unwrap();
+
+    let alice = h.new_staker(1_000).await;
+    open_position(&mut h, &alice, 300, None).await.unwrap();
+    let result = open_position(&mut h, &alice, 201, None).await.map(|_| ());
+    assert_error(result, StakingError::UserStakeCapExceeded);
+    open_position(&mut h, &alice, 200, None).await.unwrap();
+    assert_eq!(h.pool().await.total_staked, 500);
+}
+
+// 1% on every transfer, uncapped
+const TRANSFER_FEE: MintExtension = MintExtension::TransferFee {
+    bps: 100,
+    maximum_fee: u64::MAX,
+};
+
+#[tokio::test]
+async fn transfer_fees_come_off_stakes_and_budgets() {
+    let mut h = Harness::token_2022(true, &[TRANSFER_FEE]).await;
+    let alice = h.new_staker(1_000).await;
+    h.stake(&alice, 1_000, 0).await.unwrap();
+    assert_eq!(h.user_stake(&alice).await.amount, 990);
+    assert_eq!(h.pool().await.total_staked, 990);
+    let stake_vault = h.keys.stake_vault;
+    assert_eq!(h.balance(&stake_vault).await, 990);
+
+    // Only what reaches the vault is emitted
+    h.fund(0, BUDGET, START, START + DURATION).await.unwrap();
+    let stream = h.pool().await.reward_streams[0];
+    assert_eq!(stream.reward_budget, BUDGET - BUDGET / 100);
+    assert_eq!(stream.reward_rate, 990);
+    assert_eq!(h.balance(&stream.reward_vault).await, BUDGET - BUDGET / 100);
+
+    // Half the campaign is 495_000, of which 4_950 goes in the move to the stake vault
+    h.warp_to(START + DURATION / 2).await;
+    let ix = h.keys.compound(&alice.staker, None);
+    h.process(&[ix], &[&alice.keypair]).await.unwrap();
+    let restaked = 990 + 495_000 - 4_950;
+    assert_eq!(h.user_stake(&alice).await.amount, restaked);
+    assert_eq!(h.pool().await.total_staked, restaked);
+    assert_eq!(h.balance(&stake_vault).await, restaked);
+}
+
+#[tokio::test]
+async fn transfer_fees_come_off_opened_positions() {
+    let mut h = Harness::token_2022(false, &[TRANSFER_FEE]).await;
+    enable_position_nfts(&mut h).await;
+    let alice = h.new_staker(1_000).await;
+    let position = open_position(&mut h, &alice, 1_000, None).await.unwrap();
+    let user_stake: UserStake =
+        h.account(&pda::user_stake(&position.mint, &h.keys.stake_pool)).await;
+    assert_eq!(user_stake.amount, 990);
+    assert_eq!(h.pool().await.total_staked, 990);
+    let wallet_stake: WalletStake =
+        h.account(&pda::wallet_stake(&alice.staker.authority, &h.keys.stake_pool)).await;
+    assert_eq!(wallet_stake.opened, 990);
+}
+
+#[tokio::test]
+async fn mints_that_reach_into_the_vaults_are_refused() {
+    let mut h = Harness::token_2022(false, &[]).await;
+    let authority = h.authority();
+    for extension in [MintExtension::TransferHook, MintExtension::PermanentDelegate] {
+        let mint = h.create_mint_with(&[extension]).await;
+        let ix = PoolKeys::initialize_stake_pool(
+            mint,
+            h.reward_mint,
+            authority,
+            spl_token_2022::ID,
+            unlocked(),
+            0,
+            no_penalty(),
+        );
+        assert_error(h.process(&[ix], &[]).await, StakingError::UnsupportedMintExtension);
+        let ix = h.keys.add_reward_stream(authority, mint);
+        assert_error(h.process(&[ix], &[]).await, StakingError::UnsupportedMintExtension);
+    }
+}